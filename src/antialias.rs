@@ -0,0 +1,148 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use image::imageops::{self, FilterType};
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+/// Filter used to downsample the supersampled render to the requested size.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Filter {
+    /// Area average, fast and slightly soft.
+    #[default]
+    Box,
+    Triangle,
+    /// Sharpest, but slowest.
+    Lanczos3,
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "box" => Ok(Filter::Box),
+            "triangle" => Ok(Filter::Triangle),
+            "lanczos3" => Ok(Filter::Lanczos3),
+            _ => Err(anyhow!("unsupported filter: {}", s)),
+        }
+    }
+}
+
+/// Largest number of MSAA samples, the most GPUs support.
+pub const MAX_MSAA: u8 = 16;
+
+/// Checks that `msaa` is 0 (off) or a power of two up to `MAX_MSAA`.
+pub fn check_msaa(msaa: u8) -> Result<u8> {
+    match msaa == 0 || (msaa.is_power_of_two() && msaa <= MAX_MSAA) {
+        true => Ok(msaa),
+        false => Err(anyhow!(
+            "msaa must be 0 or a power of two up to {}, got {}",
+            MAX_MSAA,
+            msaa
+        )),
+    }
+}
+
+/// Antialiasing of a render: the scene is rendered `supersample` times larger,
+/// optionally with GPU multisampling, and downsampled with `filter`.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    pub filter: Filter,
+    pub supersample: u32,
+    /// Number of MSAA samples, 0 disables it, see `check_msaa`.
+    pub msaa: u8,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            filter: Filter::Box,
+            supersample: 2,
+            msaa: 0,
+        }
+    }
+}
+
+impl Settings {
+    /// Size the scene is rendered at for the requested output size.
+    pub fn render_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let factor = self.supersample.max(1);
+        (width * factor, height * factor)
+    }
+}
+
+/// Downsamples the rendered pixels to the requested size, unchanged when it already matches.
+pub fn downsample(pixels: DynamicImage, width: u32, height: u32, filter: Filter) -> DynamicImage {
+    if pixels.width() == width && pixels.height() == height {
+        return pixels;
+    }
+
+    match filter {
+        Filter::Box => imageops::thumbnail(&pixels, width, height).into(),
+        Filter::Triangle => imageops::resize(&pixels, width, height, FilterType::Triangle).into(),
+        Filter::Lanczos3 => imageops::resize(&pixels, width, height, FilterType::Lanczos3).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn test_downsample() {
+        let pixels = DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, |x, _| {
+            if x % 2 == 0 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        }));
+
+        for filter in [Filter::Box, Filter::Triangle, Filter::Lanczos3] {
+            let result = downsample(pixels.clone(), 4, 4, filter);
+            assert_eq!((result.width(), result.height()), (4, 4));
+        }
+
+        let result = downsample(pixels.clone(), 4, 4, Filter::Box).to_rgba8();
+        let gray = result.get_pixel(1, 1)[0];
+        assert!((120..=135).contains(&gray));
+
+        let result = downsample(pixels.clone(), 8, 8, Filter::Lanczos3);
+        assert_eq!(result, pixels);
+    }
+
+    #[test]
+    fn test_render_size() {
+        let settings = Settings {
+            supersample: 3,
+            ..Default::default()
+        };
+        assert_eq!(settings.render_size(100, 50), (300, 150));
+
+        let settings = Settings {
+            supersample: 0,
+            ..Default::default()
+        };
+        assert_eq!(settings.render_size(100, 50), (100, 50));
+    }
+
+    #[test]
+    fn test_check_msaa() {
+        for msaa in [0, 1, 2, 4, 8, 16] {
+            assert_eq!(check_msaa(msaa).unwrap(), msaa);
+        }
+        for msaa in [3, 6, 32, 255] {
+            assert!(check_msaa(msaa).is_err());
+        }
+    }
+
+    #[test]
+    fn test_filter_from_str() {
+        assert_eq!("Lanczos3".parse::<Filter>().unwrap(), Filter::Lanczos3);
+        assert!("nearest".parse::<Filter>().is_err());
+    }
+}
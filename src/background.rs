@@ -0,0 +1,232 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Result;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+use crate::color::Color;
+use crate::img;
+use crate::limits::Limits;
+
+/// What the render is composited on, written as `transparent`,
+/// a color (`#rrggbb`, `#rrggbbaa`) or an image url (local path or `http(s)://`,
+/// requests may only use the latter, see `img::check_request_url`).
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Background {
+    #[default]
+    Transparent,
+    Color(Color),
+    Image(String),
+}
+
+impl FromStr for Background {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "" | "transparent" => Ok(Background::Transparent),
+            color if color.starts_with('#') => Ok(Background::Color(color.parse()?)),
+            url => Ok(Background::Image(url.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for Background {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Background> for String {
+    fn from(background: Background) -> Self {
+        background.to_string()
+    }
+}
+
+impl fmt::Display for Background {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Background::Transparent => write!(f, "transparent"),
+            Background::Color(color) => write!(f, "{}", color),
+            Background::Image(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// Shadow cast by the render's silhouette onto the background.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct DropShadow {
+    /// Offset in output pixels, positive values move the shadow right and down.
+    pub offset_x: i64,
+    pub offset_y: i64,
+    /// Standard deviation of the gaussian blur in output pixels.
+    pub blur: f32,
+    /// Opacity (0-1) where the render is fully opaque.
+    pub opacity: f32,
+    pub color: Color,
+}
+
+impl Default for DropShadow {
+    fn default() -> Self {
+        Self {
+            offset_x: 0,
+            offset_y: 8,
+            blur: 8.,
+            opacity: 0.5,
+            color: Color::BLACK,
+        }
+    }
+}
+
+/// A background loaded and scaled to the output size, it can be reused for many renders.
+pub struct Layer {
+    image: Option<RgbaImage>,
+    shadow: Option<DropShadow>,
+}
+
+impl Layer {
+    /// Downloads the background image (if any) and scales it to cover `width`x`height`.
+    pub async fn load(
+        background: &Background,
+        shadow: Option<DropShadow>,
+        width: u32,
+        height: u32,
+        limits: &Limits,
+    ) -> Result<Self> {
+        let image = match background {
+            Background::Transparent => None,
+            Background::Color(color) => Some(RgbaImage::from_pixel(width, height, color.rgba())),
+            Background::Image(url) => {
+                let bytes = img::download(url.clone()).await?;
+                let image = img::decode(&bytes, limits)?;
+                Some(
+                    image
+                        .resize_to_fill(width, height, FilterType::Triangle)
+                        .to_rgba8(),
+                )
+            }
+        };
+
+        Ok(Layer { image, shadow })
+    }
+
+    /// True when compositing would not change the render.
+    pub fn is_empty(&self) -> bool {
+        self.image.is_none() && self.shadow.is_none()
+    }
+
+    /// Composites the render over the shadow and the background.
+    pub fn composite(&self, pixels: DynamicImage) -> DynamicImage {
+        if self.is_empty() {
+            return pixels;
+        }
+
+        let (width, height) = pixels.dimensions();
+        let mut result = match &self.image {
+            Some(image) if image.dimensions() == (width, height) => image.clone(),
+            Some(image) => imageops::resize(image, width, height, FilterType::Triangle),
+            None => RgbaImage::new(width, height),
+        };
+
+        if let Some(shadow) = &self.shadow {
+            let silhouette = silhouette(&pixels, shadow);
+            imageops::overlay(&mut result, &silhouette, shadow.offset_x, shadow.offset_y);
+        }
+
+        imageops::overlay(&mut result, &pixels, 0, 0);
+        result.into()
+    }
+}
+
+/// The render's alpha channel filled with the shadow color and blurred.
+fn silhouette(pixels: &DynamicImage, shadow: &DropShadow) -> RgbaImage {
+    let opacity = shadow.opacity.clamp(0., 1.);
+    let [r, g, b, a] = shadow.color.0;
+
+    let mut silhouette = RgbaImage::from_fn(pixels.width(), pixels.height(), |x, y| {
+        let alpha = pixels.get_pixel(x, y)[3] as f32 * opacity * (a as f32 / 255.);
+        Rgba([r, g, b, alpha.round() as u8])
+    });
+
+    if shadow.blur > 0. {
+        silhouette = imageops::blur(&silhouette, shadow.blur);
+    }
+
+    silhouette
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, |x, y| {
+            if (2..6).contains(&x) && (2..6).contains(&y) {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        }))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "transparent".parse::<Background>().unwrap(),
+            Background::Transparent
+        );
+        assert_eq!(
+            "#ffffff".parse::<Background>().unwrap(),
+            Background::Color(Color::WHITE)
+        );
+        assert_eq!(
+            "https://example.com/bg.jpg".parse::<Background>().unwrap(),
+            Background::Image("https://example.com/bg.jpg".to_string())
+        );
+        assert!("#zzz".parse::<Background>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_composite_color() -> Result<()> {
+        let background = Background::Color(Color::WHITE);
+        let layer = Layer::load(&background, None, 8, 8, &Limits::default()).await?;
+
+        let result = layer.composite(square()).to_rgba8();
+        assert_eq!(result.get_pixel(0, 0), &Rgba([255, 255, 255, 255]));
+        assert_eq!(result.get_pixel(3, 3), &Rgba([255, 0, 0, 255]));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_composite_shadow() -> Result<()> {
+        let shadow = DropShadow {
+            offset_x: 2,
+            offset_y: 2,
+            blur: 0.,
+            opacity: 1.,
+            color: Color::BLACK,
+        };
+        let layer = Layer::load(
+            &Background::Transparent,
+            Some(shadow),
+            8,
+            8,
+            &Limits::default(),
+        )
+        .await?;
+
+        let result = layer.composite(square()).to_rgba8();
+        assert_eq!(result.get_pixel(7, 7), &Rgba([0, 0, 0, 255]));
+        assert_eq!(result.get_pixel(3, 3), &Rgba([255, 0, 0, 255]));
+        assert_eq!(result.get_pixel(0, 0)[3], 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use image::Rgba;
+use serde::{Deserialize, Serialize};
+
+/// An sRGB color with alpha, written as `#rgb`, `#rrggbb` or `#rrggbbaa`,
+/// in json also as an `[r, g, b, a]` array.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "Written", into = "String")]
+pub struct Color(pub [u8; 4]);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Written {
+    Hex(String),
+    Rgba([u8; 4]),
+}
+
+impl Color {
+    pub const BLACK: Color = Color([0, 0, 0, 255]);
+    pub const WHITE: Color = Color([255, 255, 255, 255]);
+
+    pub fn rgba(&self) -> Rgba<u8> {
+        Rgba(self.0)
+    }
+}
+
+impl FromStr for Color {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hex = s.trim().trim_start_matches('#');
+        let invalid = || anyhow!("invalid color: {}", s);
+
+        let digits = match hex.len() {
+            3 => format!("{}ff", hex.chars().flat_map(|c| [c, c]).collect::<String>()),
+            6 => format!("{}ff", hex),
+            8 => hex.to_string(),
+            _ => return Err(invalid()),
+        };
+
+        let mut rgba = [0; 4];
+        for (i, channel) in rgba.iter_mut().enumerate() {
+            *channel = u8::from_str_radix(digits.get(i * 2..i * 2 + 2).ok_or_else(invalid)?, 16)
+                .map_err(|_| invalid())?;
+        }
+
+        Ok(Color(rgba))
+    }
+}
+
+impl TryFrom<Written> for Color {
+    type Error = anyhow::Error;
+
+    fn try_from(written: Written) -> Result<Self> {
+        match written {
+            Written::Hex(s) => s.parse(),
+            Written::Rgba(rgba) => Ok(Color(rgba)),
+        }
+    }
+}
+
+impl From<Color> for String {
+    fn from(color: Color) -> Self {
+        color.to_string()
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [r, g, b, a] = self.0;
+        write!(f, "#{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "#ff8000".parse::<Color>().unwrap(),
+            Color([255, 128, 0, 255])
+        );
+        assert_eq!(
+            "ff800080".parse::<Color>().unwrap(),
+            Color([255, 128, 0, 128])
+        );
+        assert_eq!("#fff".parse::<Color>().unwrap(), Color::WHITE);
+        assert!("#ff80".parse::<Color>().is_err());
+        assert!("#gggggg".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn test_serde() {
+        let color: Color = serde_json::from_str("\"#000000\"").unwrap();
+        assert_eq!(color, Color::BLACK);
+        assert_eq!(serde_json::to_string(&color).unwrap(), "\"#000000ff\"");
+
+        let color: Color = serde_json::from_str("[255, 128, 0, 128]").unwrap();
+        assert_eq!(color, Color([255, 128, 0, 128]));
+        assert!(serde_json::from_str::<Color>("[255, 128, 0]").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+/// How the render is blended onto the mask (e.g. a product photo).
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlendMode {
+    /// Keeps the shading of the photo, the usual choice for mockups.
+    #[default]
+    Multiply,
+    Overlay,
+    Screen,
+    /// Paints the render over the photo.
+    Normal,
+}
+
+impl FromStr for BlendMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "multiply" => Ok(BlendMode::Multiply),
+            "overlay" => Ok(BlendMode::Overlay),
+            "screen" => Ok(BlendMode::Screen),
+            "normal" => Ok(BlendMode::Normal),
+            _ => Err(anyhow!("unsupported blend mode: {}", s)),
+        }
+    }
+}
+
+impl BlendMode {
+    /// Blends a backdrop channel with a source channel, both in 0-1.
+    pub(crate) fn apply(&self, backdrop: f32, source: f32) -> f32 {
+        match self {
+            BlendMode::Normal => source,
+            BlendMode::Multiply => backdrop * source,
+            BlendMode::Screen => backdrop + source - backdrop * source,
+            BlendMode::Overlay => {
+                if backdrop <= 0.5 {
+                    2. * backdrop * source
+                } else {
+                    1. - 2. * (1. - backdrop) * (1. - source)
+                }
+            }
+        }
+    }
+}
+
+/// Size of a render for a mask, the mask's size scaled down to fit into the maximum.
+pub fn fit(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    if width <= max_width && height <= max_height {
+        return (width, height);
+    }
+
+    let scale = (max_width as f64 / width as f64).min(max_height as f64 / height as f64);
+    (
+        ((width as f64 * scale).round() as u32).max(1),
+        ((height as f64 * scale).round() as u32).max(1),
+    )
+}
+
+/// Size scaled down to at most `max_pixels` pixels, keeping the aspect ratio.
+pub fn fit_pixels(width: u32, height: u32, max_pixels: u64) -> (u32, u32) {
+    if width as u64 * height as u64 <= max_pixels {
+        return (width, height);
+    }
+
+    // rounded down, so the scaled size never ends up above the maximum
+    let scale = (max_pixels as f64 / (width as f64 * height as f64)).sqrt();
+    (
+        ((width as f64 * scale).floor() as u32).max(1),
+        ((height as f64 * scale).floor() as u32).max(1),
+    )
+}
+
+/// Blends `top` (the render) onto `bottom` (the mask), `top` is resized to the size of `bottom`.
+///
+/// Transparent parts of `top` leave `bottom` untouched, the blended colors are mixed in
+/// by the alpha of `top` and the result is alpha composited like a normal layer.
+pub fn blend(bottom: &DynamicImage, top: &DynamicImage, mode: BlendMode) -> DynamicImage {
+    let bottom = bottom.to_rgba8();
+    let top = if top.dimensions() != bottom.dimensions() {
+        imageops::resize(top, bottom.width(), bottom.height(), FilterType::Triangle)
+    } else {
+        top.to_rgba8()
+    };
+
+    let result = RgbaImage::from_fn(bottom.width(), bottom.height(), |x, y| {
+        blend_pixel(bottom.get_pixel(x, y), top.get_pixel(x, y), mode)
+    });
+
+    result.into()
+}
+
+fn blend_pixel(backdrop: &Rgba<u8>, source: &Rgba<u8>, mode: BlendMode) -> Rgba<u8> {
+    let alpha_backdrop = backdrop[3] as f32 / 255.;
+    let alpha_source = source[3] as f32 / 255.;
+    let alpha = alpha_source + alpha_backdrop * (1. - alpha_source);
+
+    if alpha == 0. {
+        return Rgba([0, 0, 0, 0]);
+    }
+
+    let mut result = [0; 4];
+    for (i, channel) in result.iter_mut().take(3).enumerate() {
+        let cb = backdrop[i] as f32 / 255.;
+        let cs = source[i] as f32 / 255.;
+
+        // where the backdrop is transparent the source is kept as is
+        let mixed = (1. - alpha_backdrop) * cs + alpha_backdrop * mode.apply(cb, cs);
+        let premultiplied = alpha_source * mixed + (1. - alpha_source) * alpha_backdrop * cb;
+
+        *channel = (premultiplied / alpha * 255.).round().clamp(0., 255.) as u8;
+    }
+    result[3] = (alpha * 255.).round() as u8;
+
+    Rgba(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(color: [u8; 4]) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, Rgba(color)))
+    }
+
+    fn blended(bottom: [u8; 4], top: [u8; 4], mode: BlendMode) -> [u8; 4] {
+        blend(&pixel(bottom), &pixel(top), mode)
+            .to_rgba8()
+            .get_pixel(0, 0)
+            .0
+    }
+
+    #[test]
+    fn test_blend_modes() {
+        let gray = [128, 128, 128, 255];
+        let red = [255, 0, 0, 255];
+
+        assert_eq!(blended(gray, red, BlendMode::Normal), red);
+        assert_eq!(blended(gray, red, BlendMode::Multiply), [128, 0, 0, 255]);
+        assert_eq!(blended(gray, red, BlendMode::Screen), [255, 128, 128, 255]);
+        assert_eq!(blended(gray, red, BlendMode::Overlay), [255, 1, 1, 255]);
+    }
+
+    #[test]
+    fn test_blend_alpha() {
+        let gray = [128, 128, 128, 255];
+
+        // a transparent render keeps the mask
+        assert_eq!(blended(gray, [255, 0, 0, 0], BlendMode::Multiply), gray);
+        // a transparent mask keeps the render
+        assert_eq!(
+            blended([0, 0, 0, 0], [255, 0, 0, 255], BlendMode::Multiply),
+            [255, 0, 0, 255]
+        );
+        // half transparent render over an opaque mask
+        assert_eq!(
+            blended([255, 255, 255, 255], [0, 0, 0, 128], BlendMode::Normal),
+            [127, 127, 127, 255]
+        );
+    }
+
+    #[test]
+    fn test_blend_resizes_top() {
+        let bottom = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 2, Rgba([255; 4])));
+        let result = blend(&bottom, &pixel([0, 0, 0, 255]), BlendMode::Multiply);
+        assert_eq!(result.dimensions(), (4, 2));
+    }
+
+    #[test]
+    fn test_fit() {
+        assert_eq!(fit(100, 50, 200, 200), (100, 50));
+        assert_eq!(fit(4000, 2000, 2000, 2000), (2000, 1000));
+        assert_eq!(fit(1000, 4000, 2000, 2000), (500, 2000));
+    }
+
+    #[test]
+    fn test_fit_pixels() {
+        assert_eq!(fit_pixels(100, 50, 5000), (100, 50));
+        assert_eq!(fit_pixels(200, 100, 5000), (100, 50));
+        let (width, height) = fit_pixels(4000, 3000, 1_000_000);
+        assert!(width as u64 * height as u64 <= 1_000_000);
+        assert_eq!((width, height), (1154, 866));
+    }
+}
@@ -0,0 +1,43 @@
+use std::path::Path;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::{Arg, ArgMatches, Command};
+
+use crate::server::config::Config;
+
+pub struct ConfigCheck {}
+
+#[async_trait]
+impl crate::Subcommand for ConfigCheck {
+    fn get_subcommand(&self) -> Command {
+        Command::new("config")
+            .subcommand_required(true)
+            .subcommand(
+                Command::new("check")
+                    .arg(
+                        Arg::new("config")
+                            .long("config")
+                            .default_value("config.toml")
+                            .long_help("path to the config file, GIMME3D_* variables override it"),
+                    )
+                    .about("Validate the config and print the effective one, with the defaults and overrides applied"),
+            )
+            .about("Inspect the server config")
+    }
+
+    async fn run(&self, matches: &ArgMatches) -> Result<()> {
+        if let Some(("check", submatches)) = matches.subcommand() {
+            let config_path = submatches.get_one::<String>("config").unwrap();
+            print!("{}", check(Path::new(config_path))?);
+        }
+
+        Ok(())
+    }
+}
+
+/// The effective config as toml, fails like `serve` would on a malformed config or override.
+pub fn check(path: &Path) -> Result<String> {
+    let config = Config::load(path)?;
+    Ok(toml::to_string_pretty(&config)?)
+}
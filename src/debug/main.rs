@@ -2,44 +2,23 @@ use std::path::{Path, PathBuf};
 
 use anyhow::anyhow;
 use anyhow::Result;
-use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
 use three_d::*;
 
+use gimme_3d::composite::{self, BlendMode};
+use gimme_3d::limits::Limits;
+use gimme_3d::manifest::Manifest;
+use gimme_3d::mockup::{MapCache, Mockup};
+use gimme_3d::render;
+use gimme_3d::storage;
+
 #[tokio::main]
 async fn main() {
-    let mask_files = walk_directory("masks".to_string());
-
     let canvas = "testdata/canvas.png".to_string();
 
     let context = HeadlessContext::new().unwrap();
     let _ = std::fs::create_dir("results");
     let _ = std::fs::create_dir("textures");
 
-    // run(
-    //     &context,
-    //     "masks/s1_p1_hoodie/00_s1_p1_hoodie.webp".to_string(),
-    //     canvas.to_string(),
-    // ).await.unwrap();
-    // run(
-    //     &context,
-    //     "masks/s1_p1_t-shirt/00_s1_p1_t-shirt.webp".to_string(),
-    //     canvas.to_string(),
-    // ).await.unwrap();
-    // run(
-    //     &context,
-    //     "masks/s1_p1_notebook/00_s1_p1_notebook_a5.webp".to_string(),
-    //     canvas.to_string(),
-    // ).await.unwrap();
-    // run(
-    //     &context,
-    //     "masks/s0_p3_towel-bath/00_s0_p3_towel-bath.png".to_string(),
-    //     canvas.to_string(),
-    // ).await.unwrap();
-    // run(
-    //     &context,
-    //     "masks/s1_p1_set-pillowcase-duvet-cover/00_s1_p1_set-pillowcase-duvet-cover.webp".to_string(),
-    //     canvas.to_string(),
-    // ).await.unwrap();
     run(
         &context,
         "masks/s3_p4_cushion-decorative/00_s3_p4_cushion-decorative_400x400.webp".to_string(),
@@ -47,31 +26,6 @@ async fn main() {
     )
     .await
     .unwrap();
-    return;
-
-    for mask in mask_files {
-        let result = run(&context, mask.clone(), canvas.to_string()).await;
-        if let Err(e) = result {
-            println!("Error in {}: {}", mask, e);
-        }
-    }
-}
-
-fn walk_directory(dir: String) -> Vec<String> {
-    let mut result = vec![];
-
-    for entry in std::fs::read_dir(dir).unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-
-        if path.is_dir() {
-            result.append(&mut walk_directory(path.to_str().unwrap().to_string()));
-        } else {
-            result.push(path.to_str().unwrap().to_string());
-        }
-    }
-
-    result
 }
 
 async fn run(context: &HeadlessContext, mask: String, canvas: String) -> Result<()> {
@@ -97,35 +51,47 @@ async fn run(context: &HeadlessContext, mask: String, canvas: String) -> Result<
     let mask = image::open(mask).unwrap();
     let texture_bytes = std::fs::read(canvas).unwrap();
 
-    const UPSCALE: u32 = 2;
+    let model_path = Path::new("glb").join(model_file.clone());
 
-    let pixels = gimme_3d::render::render_raw_images(
-        Some(
-            Path::new("glb")
-                .join(model_file.clone())
-                .to_str()
-                .unwrap()
-                .to_string(),
-        ),
+    let texture = gimme_3d::render::render_raw_images(
+        Some(model_path.to_str().unwrap().to_string()),
         None,
         vec![texture_bytes],
         context,
-        mask.width() * UPSCALE,
-        mask.height() * UPSCALE,
+        mask.width(),
+        mask.height(),
+        // the default antialias settings, like the server and the `render` command
+        &render::Options::default(),
+        &Limits::default(),
         &String::new(),
+        &storage::Settings::default(),
     )
     .await?;
 
-    let texture: DynamicImage = image::imageops::resize(
-        &pixels,
-        mask.width(),
-        mask.height(),
-        image::imageops::FilterType::Lanczos3,
-    )
-    .into();
     texture.save(Path::new("textures").join(Path::new(&model_file).with_extension("png")))?;
 
-    let result = multiply(&mask, &texture);
+    // the mockup maps of the product's manifest, like the server applies them to a masked render
+    let texture = match Manifest::load(&model_path)?.and_then(|manifest| {
+        let dir = manifest.dir.to_string_lossy().to_string();
+        manifest.mockup.map(|maps| (maps, dir))
+    }) {
+        Some((maps, dir)) => {
+            let (width, height) = (mask.width(), mask.height());
+            Mockup::load(
+                &maps,
+                &dir,
+                width,
+                height,
+                &Limits::default(),
+                &MapCache::default(),
+            )
+            .await?
+            .apply(texture)
+        }
+        None => texture,
+    };
+
+    let result = composite::blend(&mask, &texture, BlendMode::Multiply);
 
     let mut writer = std::fs::File::create(
         Path::new("results")
@@ -139,47 +105,3 @@ async fn run(context: &HeadlessContext, mask: String, canvas: String) -> Result<
 
     Ok(())
 }
-
-/// images should have the same dimensions
-fn multiply(bottom: &DynamicImage, top_raw: &DynamicImage) -> DynamicImage {
-    let top = if top_raw.dimensions() != bottom.dimensions() {
-        top_raw.resize(
-            bottom.width(),
-            bottom.height(),
-            image::imageops::FilterType::Triangle,
-        )
-    } else {
-        top_raw.clone()
-    };
-
-    let mut result_image = RgbaImage::new(bottom.width(), bottom.height());
-
-    for y in 0..bottom.height() {
-        for x in 0..bottom.width() {
-            let pixel1 = bottom.get_pixel(x, y);
-            let pixel2 = top.get_pixel(x, y);
-
-            let mut result = vec![];
-            (0..3).for_each(|i| {
-                let ch1 = pixel1[i] as f32 / 255.;
-                let ch2 = pixel2[i] as f32 / 255.;
-
-                if ch1 == 0. || ch2 == 0. {
-                    result.push((ch1.max(ch2) * 255.) as u8);
-                    return;
-                }
-
-                result.push((ch1 * ch2 * 255.) as u8);
-            });
-
-            result.push(pixel1[3]);
-
-            let product: [u8; 4] = result.try_into().unwrap();
-            let product = Rgba(product);
-
-            result_image.put_pixel(x, y, product);
-        }
-    }
-
-    result_image.into()
-}
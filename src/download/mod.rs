@@ -1,14 +1,20 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use clap::{Arg, ArgMatches, Command};
 use futures_util::{stream, StreamExt};
 use indicatif::ProgressBar;
-use url::Url;
+use reqwest::StatusCode;
+use sha1::{Digest, Sha1};
 
+use crate::error::Error;
 use crate::server;
+use crate::storage::{self, Resume, Storage, Streamed};
 
 pub struct Download {}
 
@@ -21,18 +27,105 @@ impl crate::Subcommand for Download {
                     .default_value("config.toml")
                     .long_help("path to config.toml to be used"),
             )
+            .arg(Arg::new("checksums").long("checksums").long_help(
+                "file of expected sha1 checksums in the format of sha1sum (`<sha1>  <model>`)",
+            ))
+            .arg(
+                Arg::new("retries")
+                    .long("retries")
+                    .default_value("3")
+                    .value_parser(clap::value_parser!(u32))
+                    .long_help(
+                        "how often a failed download is retried (network errors, 5xx and 429)",
+                    ),
+            )
+            .arg(
+                Arg::new("force")
+                    .long("force")
+                    .action(clap::ArgAction::SetTrue)
+                    .long_help("download every model, even when the server reports it unchanged"),
+            )
             .about("Download models from a remote server to a local directory (for caching)")
     }
 
     async fn run(&self, matches: &ArgMatches) -> Result<()> {
         let config_path = matches.get_one::<String>("config").unwrap();
-        let config = server::config::Config::parse_toml(config_path.to_string()).unwrap();
-        download_models(
+        let config = server::config::Config::load(Path::new(config_path))?;
+        let checksums = match matches.get_one::<String>("checksums") {
+            Some(path) => parse_checksums(
+                &std::fs::read_to_string(path)
+                    .with_context(|| format!("reading checksums {}", path))?,
+            )?,
+            None => HashMap::new(),
+        };
+        let options = Options {
+            checksums,
+            retries: *matches.get_one::<u32>("retries").unwrap(),
+            force: matches.get_flag("force"),
+        };
+
+        let summary = download_models(
             config.models.models_base_url,
             config.models.models,
             config.models.local_model_dir,
+            &config.storage,
+            &options,
         )
-        .await
+        .await?;
+
+        println!("{}", summary);
+        if !summary.failed.is_empty() {
+            return Err(anyhow!(
+                "{} of {} models failed",
+                summary.failed.len(),
+                summary.total()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+struct Options {
+    /// Expected sha1 of the models, by file name.
+    checksums: HashMap<String, String>,
+    retries: u32,
+    /// Ignores the stored ETags.
+    force: bool,
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Downloaded,
+    Unchanged,
+}
+
+#[derive(Default)]
+struct Summary {
+    downloaded: usize,
+    unchanged: usize,
+    failed: Vec<(String, String)>,
+}
+
+impl Summary {
+    fn total(&self) -> usize {
+        self.downloaded + self.unchanged + self.failed.len()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} downloaded, {} unchanged, {} failed",
+            self.downloaded,
+            self.unchanged,
+            self.failed.len()
+        )?;
+        for (model, error) in &self.failed {
+            write!(f, "\n  {}: {}", model, error)?;
+        }
+        Ok(())
     }
 }
 
@@ -40,11 +133,12 @@ async fn download_models(
     mut base_url: String,
     models: Vec<String>,
     output_dir: String,
-) -> Result<()> {
+    storage: &storage::Settings,
+    options: &Options,
+) -> Result<Summary> {
     if !base_url.ends_with('/') {
         base_url.push('/');
     }
-    let base = Url::parse(base_url.as_str())?;
     let output = Path::new(output_dir.as_str());
 
     if !output.exists() {
@@ -52,31 +146,380 @@ async fn download_models(
     }
 
     let pb = ProgressBar::new(models.len() as u64);
+    let storage = storage::for_location(&base_url, storage);
 
-    stream::iter(models)
-        .for_each_concurrent(10, |model| {
-            let base = base.clone();
-            let url = base.join(model.as_str()).unwrap();
-            let output = output.join(model.as_str()).to_owned();
-            async {
-                download(url, output).await.unwrap();
-                pb.clone().inc(1);
+    let results: Vec<(String, Result<Outcome>)> = stream::iter(models)
+        .map(|model| {
+            let storage = storage.as_ref();
+            let location = format!("{}{}", base_url, model);
+            let pb = &pb;
+            async move {
+                let expected = options.checksums.get(&model).map(String::as_str);
+                let result =
+                    fetch(storage, &location, output.join(&model), expected, options).await;
+                pb.inc(1);
+                (model, result)
             }
         })
+        .buffer_unordered(10)
+        .collect()
         .await;
 
     pb.finish_with_message("done");
 
-    Ok(())
+    let mut summary = Summary::default();
+    for (model, result) in results {
+        match result {
+            Ok(Outcome::Downloaded) => summary.downloaded += 1,
+            Ok(Outcome::Unchanged) => summary.unchanged += 1,
+            Err(e) => summary.failed.push((model, one_line(&format!("{:#}", e)))),
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Downloads a model unless the local copy matches its checksum or ETag, retrying transient failures.
+async fn fetch(
+    storage: &dyn Storage,
+    location: &str,
+    output: PathBuf,
+    expected: Option<&str>,
+    options: &Options,
+) -> Result<Outcome> {
+    // a local copy with the expected checksum needs no request
+    if let (Some(expected), Ok(actual)) = (expected, file_sha1_hex(&output)) {
+        if actual == expected {
+            return Ok(Outcome::Unchanged);
+        }
+    }
+
+    let etag = match !options.force && expected.is_none() && output.exists() {
+        true => std::fs::read_to_string(etag_path(&output)).ok(),
+        false => None,
+    };
+
+    let mut attempt = 0;
+    loop {
+        match download(storage, location, &output, etag.as_deref(), expected).await {
+            Err(e) if attempt < options.retries && is_retryable(&e) => {
+                attempt += 1;
+                let backoff = Duration::from_millis(500 * 2u64.pow(attempt - 1));
+                log::warn!("Retrying {} in {:?}: {:#}", location, backoff, e);
+                tokio::time::sleep(backoff).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Streams the model into a file next to its final path and renames it once it is verified,
+/// so a failed download never replaces a good copy and large models are not held in memory.
+/// An interrupted download is kept and continued by the next attempt while the model keeps its ETag.
+async fn download(
+    storage: &dyn Storage,
+    location: &str,
+    output: &Path,
+    etag: Option<&str>,
+    expected: Option<&str>,
+) -> Result<Outcome> {
+    let mut partial = Partial::open(partial_path(output))?;
+    let resume_etag = partial.resume_etag();
+    let resume = resume_etag.as_deref().map(|etag| Resume {
+        offset: partial.len,
+        etag,
+    });
+
+    let etag = match storage
+        .fetch_to(location, etag, resume, &mut partial)
+        .await?
+    {
+        Streamed::Written { etag } => etag,
+        Streamed::Unchanged => {
+            partial.remove();
+            return Ok(Outcome::Unchanged);
+        }
+    };
+
+    let actual = format!("{:x}", partial.sha1.clone().finalize());
+    if let Err(e) = verify(location, expected, actual).and_then(|_| Ok(partial.file.sync_all()?)) {
+        partial.remove();
+        return Err(e);
+    }
+    std::fs::rename(&partial.path, output)?;
+    let _ = std::fs::remove_file(etag_path(&partial.path));
+
+    match etag {
+        Some(etag) => std::fs::write(etag_path(output), etag)?,
+        None => {
+            let _ = std::fs::remove_file(etag_path(output));
+        }
+    }
+
+    Ok(Outcome::Downloaded)
+}
+
+fn verify(location: &str, expected: Option<&str>, actual: String) -> Result<()> {
+    match expected {
+        Some(expected) if actual != expected => Err(Error::ChecksumMismatch {
+            url: location.to_string(),
+            expected: expected.to_string(),
+            actual,
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// The file a model is downloaded into and the hash of what it holds. The ETag of the model
+/// is kept beside it, so an interrupted download can be continued.
+struct Partial {
+    path: PathBuf,
+    file: std::fs::File,
+    sha1: Sha1,
+    len: u64,
+}
+
+impl Partial {
+    /// Opens the file left by an earlier attempt, or a new one.
+    fn open(path: PathBuf) -> Result<Self> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut sha1 = Sha1::new();
+        let len = std::io::copy(&mut file, &mut sha1)?;
+
+        Ok(Partial {
+            path,
+            file,
+            sha1,
+            len,
+        })
+    }
+
+    /// The ETag to continue the download with, weak ETags can't be used with `If-Range`.
+    fn resume_etag(&self) -> Option<String> {
+        std::fs::read_to_string(etag_path(&self.path))
+            .ok()
+            .filter(|etag| self.len > 0 && !etag.starts_with("W/"))
+    }
+
+    fn remove(&self) {
+        let _ = std::fs::remove_file(&self.path);
+        let _ = std::fs::remove_file(etag_path(&self.path));
+    }
+}
+
+impl storage::Sink for Partial {
+    fn start(&mut self, etag: Option<&str>, resumed: bool) -> std::io::Result<()> {
+        if !resumed {
+            self.file.set_len(0)?;
+            self.sha1 = Sha1::new();
+            self.len = 0;
+        }
+
+        match etag {
+            Some(etag) => std::fs::write(etag_path(&self.path), etag),
+            None => match std::fs::remove_file(etag_path(&self.path)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            },
+        }
+    }
+}
+
+impl Write for Partial {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.sha1.update(&buf[..written]);
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Network errors, server errors, rate limiting and corrupted transfers are worth another try.
+fn is_retryable(e: &anyhow::Error) -> bool {
+    if e.downcast_ref::<reqwest::Error>().is_some() {
+        return true;
+    }
+
+    match e.downcast_ref::<Error>() {
+        Some(Error::ModelDownloadError { status_code, .. }) => {
+            status_code.is_server_error() || *status_code == StatusCode::TOO_MANY_REQUESTS
+        }
+        Some(Error::ChecksumMismatch { .. }) => true,
+        _ => false,
+    }
+}
+
+/// Error pages of the server can be long, the summary keeps one line per model.
+fn one_line(message: &str) -> String {
+    let line = message.split_whitespace().collect::<Vec<_>>().join(" ");
+    match line.char_indices().nth(160) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line,
+    }
+}
+
+fn partial_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".part");
+    PathBuf::from(path)
 }
 
-async fn download(url: Url, output: PathBuf) -> Result<()> {
-    let mut response = reqwest::get(url).await?;
-    let mut file = std::fs::File::create(output.clone())?;
+/// The ETag of the local copy is kept beside it for conditional requests.
+fn etag_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".etag");
+    PathBuf::from(path)
+}
+
+fn file_sha1_hex(path: &Path) -> std::io::Result<String> {
+    let mut sha1 = Sha1::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut sha1)?;
+    Ok(format!("{:x}", sha1.finalize()))
+}
+
+/// Parses the output of `sha1sum`, `<sha1>  <file name>` per line.
+fn parse_checksums(content: &str) -> Result<HashMap<String, String>> {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (checksum, file_name) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| anyhow!("invalid checksum line: {}", line))?;
+            let file_name = file_name.trim_start().trim_start_matches('*');
+            let file_name = Path::new(file_name)
+                .file_name()
+                .ok_or_else(|| anyhow!("invalid checksum line: {}", line))?;
+
+            Ok((
+                file_name.to_string_lossy().to_string(),
+                checksum.to_lowercase(),
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_checksums() -> Result<()> {
+        let checksums = parse_checksums(
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709  foo.glb\n\
+             DA39A3EE5E6B4B0D3255BFEF95601890AFD80709 *models/bar.glb\n",
+        )?;
+        let empty = format!("{:x}", Sha1::digest(b""));
+        assert_eq!(checksums["foo.glb"], empty);
+        assert_eq!(checksums["bar.glb"], empty);
+        assert!(parse_checksums("foo.glb").is_err());
 
-    while let Some(chunk) = response.chunk().await? {
-        file.write_all(&chunk)?;
+        Ok(())
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_download() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("gimme-3d-download-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let output = dir.join("canary.glb");
+        let expected = file_sha1_hex(Path::new("testdata/canary.glb"))?;
+        let location = "testdata/canary.glb";
+
+        let result = download(&storage::Local, location, &output, None, Some("0000")).await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<Error>(),
+            Some(Error::ChecksumMismatch { .. })
+        ));
+        assert!(!output.exists() && !partial_path(&output).exists());
+
+        download(&storage::Local, location, &output, None, Some(&expected)).await?;
+        assert_eq!(file_sha1_hex(&output)?, expected);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_resumes() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use warp::Filter;
+
+        let model = std::fs::read("testdata/canary.glb")?;
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        // serves the rest of the model after `Range` while `If-Range` matches its ETag
+        let (body, counter) = (model.clone(), sent.clone());
+        let stub = warp::path("canary.glb")
+            .and(warp::header::optional::<String>("range"))
+            .and(warp::header::optional::<String>("if-range"))
+            .map(move |range: Option<String>, if_range: Option<String>| {
+                let offset = match (range, if_range.as_deref()) {
+                    (Some(range), Some("\"v1\"")) => range
+                        .trim_start_matches("bytes=")
+                        .trim_end_matches('-')
+                        .parse()
+                        .unwrap(),
+                    _ => 0,
+                };
+                counter.fetch_add(body.len() - offset, Ordering::SeqCst);
+                let status = match offset {
+                    0 => warp::http::StatusCode::OK,
+                    _ => warp::http::StatusCode::PARTIAL_CONTENT,
+                };
+                warp::http::Response::builder()
+                    .status(status)
+                    .header("etag", "\"v1\"")
+                    .body(body[offset..].to_vec())
+                    .unwrap()
+            });
+        let (addr, server) = warp::serve(stub).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let dir = std::env::temp_dir().join(format!("gimme-3d-resume-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let output = dir.join("canary.glb");
+        let partial = partial_path(&output);
+        let location = format!("http://{}/canary.glb", addr);
+        let expected = format!("{:x}", Sha1::digest(&model));
+
+        for (etag, expected_sent) in [("\"v1\"", model.len() - 100), ("\"v0\"", model.len())] {
+            sent.store(0, Ordering::SeqCst);
+            std::fs::write(&partial, &model[..100])?;
+            std::fs::write(etag_path(&partial), etag)?;
+
+            let storage = storage::Http::default();
+            download(&storage, &location, &output, None, Some(&expected)).await?;
+            assert_eq!(file_sha1_hex(&output)?, expected);
+            assert_eq!(sent.load(Ordering::SeqCst), expected_sent);
+            assert!(!partial.exists() && !etag_path(&partial).exists());
+            assert_eq!(std::fs::read_to_string(etag_path(&output))?, "\"v1\"");
+        }
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let status = |status_code| {
+            anyhow::Error::from(Error::ModelDownloadError {
+                status_code,
+                message: "".to_string(),
+            })
+        };
+        assert!(is_retryable(&status(StatusCode::BAD_GATEWAY)));
+        assert!(is_retryable(&status(StatusCode::TOO_MANY_REQUESTS)));
+        assert!(!is_retryable(&status(StatusCode::FORBIDDEN)));
+        assert!(!is_retryable(&anyhow!("disk full")));
+    }
 }
@@ -14,8 +14,11 @@ pub enum Error {
     #[error("Gltf parsing error: {0}")]
     GltfParsingError(gltf::Error),
 
-    #[error("No default scene")]
-    NoDefaultScene,
+    #[error("No scene")]
+    NoScene,
+
+    #[error("Scene not found: {scene}, the model has scenes: {scenes}")]
+    SceneNotFound { scene: String, scenes: String },
 
     #[error("No camera")]
     NoCamera,
@@ -23,6 +26,27 @@ pub enum Error {
     #[error("No mesh")]
     NoMesh,
 
+    #[error("No node matches: {0}")]
+    NodeNotFound(String),
+
+    #[error("Invalid node pattern {pattern}: {message}")]
+    InvalidNodePattern { pattern: String, message: String },
+
+    #[error("Camera not found: {0}")]
+    CameraNotFound(String),
+
+    #[error("Unknown product: {0}")]
+    UnknownProduct(String),
+
+    #[error("Model location not allowed: {0}")]
+    ForbiddenLocation(String),
+
+    #[error("Callback url not allowed: {0}")]
+    ForbiddenCallback(String),
+
+    #[error("Image location not allowed, only http(s) urls are: {0}")]
+    ForbiddenImageLocation(String),
+
     #[error("No local model found at: {0}")]
     NoLocalModel(String),
 
@@ -38,8 +62,18 @@ pub enum Error {
         message: String,
     },
 
+    #[error("Checksum mismatch of {url}: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+
     #[error("No textures")]
     NoTextures,
+
+    #[error("Invalid mask: {0}")]
+    InvalidMask(String),
 }
 
 #[derive(Debug, Error)]
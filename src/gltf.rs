@@ -1,10 +1,103 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
 use gltf::camera::Projection;
+use gltf::mesh::Semantic;
 use gltf::scene::iter;
 use gltf::{Node, Scene};
 
+use nalgebra::Matrix4;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
 use crate::object;
 use crate::object::Transform;
 
+/// A scene of a model by index or name.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum SceneSelector {
+    Index(usize),
+    Name(String),
+}
+
+impl FromStr for SceneSelector {
+    type Err = std::convert::Infallible;
+
+    /// A number is an index, anything else a name.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(index) => SceneSelector::Index(index),
+            Err(_) => SceneSelector::Name(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for SceneSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneSelector::Index(index) => write!(f, "{}", index),
+            SceneSelector::Name(name) => write!(f, "\"{}\"", name),
+        }
+    }
+}
+
+/// Index of the scene to render: the selected one, else the default scene, else the first.
+pub fn scene_index(doc: &gltf::Document, selector: Option<&SceneSelector>) -> Result<usize, Error> {
+    let found = match selector {
+        Some(SceneSelector::Index(index)) => doc.scenes().nth(*index),
+        Some(SceneSelector::Name(name)) => doc.scenes().find(|scene| scene.name() == Some(name)),
+        None => doc.default_scene().or_else(|| doc.scenes().next()),
+    };
+
+    match (found, selector) {
+        (Some(scene), _) => Ok(scene.index()),
+        (None, None) => Err(Error::NoScene),
+        (None, Some(selector)) => Err(Error::SceneNotFound {
+            scene: selector.to_string(),
+            scenes: scene_list(doc),
+        }),
+    }
+}
+
+/// The scenes of a model for error messages, e.g. `0 "Main", 1`.
+fn scene_list(doc: &gltf::Document) -> String {
+    let scenes: Vec<String> = doc
+        .scenes()
+        .map(|scene| match scene.name() {
+            Some(name) => format!("{} \"{}\"", scene.index(), name),
+            None => scene.index().to_string(),
+        })
+        .collect();
+
+    match scenes.is_empty() {
+        true => "none".to_string(),
+        false => scenes.join(", "),
+    }
+}
+
+/// Rewrites a glb/gltf so the scene at `index` is the first and default one,
+/// `three_d_asset` only ever loads the first scene. Buffers are left untouched.
+pub fn with_first_scene(
+    bytes: &[u8],
+    doc: gltf::Document,
+    index: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let mut root = doc.into_json();
+    root.scenes.swap(0, index);
+    root.scene = Some(gltf::json::Index::new(0));
+    let json = root.to_vec()?;
+
+    if !bytes.starts_with(b"glTF") {
+        return Ok(json);
+    }
+
+    let mut glb = gltf::binary::Glb::from_slice(bytes)?;
+    glb.json = Cow::Owned(json);
+    Ok(glb.to_vec()?)
+}
+
 pub fn extract<T: Clone>(scene: &Scene, parse_fn: fn(&Node, Transform) -> Option<T>) -> Option<T> {
     for node in scene.nodes() {
         let carry = object::Transform::from(node.transform());
@@ -47,6 +140,7 @@ pub fn get_camera(node: &Node, carry: Transform) -> Option<object::Camera> {
     if let Some(camera) = node.camera() {
         if let Projection::Perspective(perspective) = camera.projection() {
             return Some(object::Camera {
+                name: node.name().map(String::from),
                 parent_transform: carry,
                 transform: object::Transform::from(node.transform()),
                 aspect_ratio: perspective.aspect_ratio().unwrap_or(1.0),
@@ -62,6 +156,7 @@ pub fn get_camera(node: &Node, carry: Transform) -> Option<object::Camera> {
 pub fn get_mesh(node: &Node, carry: Transform) -> Option<object::Mesh> {
     if node.mesh().is_some() {
         return Some(object::Mesh {
+            name: node.name().map(String::from),
             parent_transform: carry,
             transform: object::Transform::from(node.transform()),
         });
@@ -69,6 +164,125 @@ pub fn get_mesh(node: &Node, carry: Transform) -> Option<object::Mesh> {
     None
 }
 
+/// Names of the nodes of the parts of a `three_d::Model` loaded from the document, in the order
+/// of the parts, see `visit_parts`.
+pub fn part_names(doc: &gltf::Document) -> Vec<String> {
+    let mut names = vec![];
+    visit_parts(doc, &mut |node, _| {
+        names.push(
+            node.name()
+                .map(String::from)
+                .unwrap_or_else(|| format!("index {}", node.index())),
+        )
+    });
+    names
+}
+
+/// Which nodes of a model are rendered, by node or mesh name or glob (`*`, `?`, `[...]`).
+/// A pattern matching a node applies to its children too.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Visibility {
+    /// Only these nodes are rendered, all of them when empty.
+    pub include: Vec<String>,
+    /// These nodes are not rendered, even when included.
+    pub exclude: Vec<String>,
+}
+
+impl Visibility {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+}
+
+/// Whether each part of a `three_d::Model` loaded from the document is rendered, in the order
+/// of the parts. Fails on a pattern that matches none of the rendered nodes, which is a typo
+/// more often than not.
+pub fn part_visibility(doc: &gltf::Document, visibility: &Visibility) -> Result<Vec<bool>, Error> {
+    let compile = |patterns: &[String]| -> Result<Vec<glob::Pattern>, Error> {
+        patterns
+            .iter()
+            .map(|pattern| {
+                glob::Pattern::new(pattern).map_err(|e| Error::InvalidNodePattern {
+                    pattern: pattern.clone(),
+                    message: e.msg.to_string(),
+                })
+            })
+            .collect()
+    };
+    let include = compile(&visibility.include)?;
+    let exclude = compile(&visibility.exclude)?;
+
+    let mut matched = vec![false; include.len() + exclude.len()];
+    let mut visible = vec![];
+    visit_parts(doc, &mut |_, names| {
+        let mut matches = |patterns: &[glob::Pattern], offset: usize| {
+            let mut any = false;
+            for (i, pattern) in patterns.iter().enumerate() {
+                if names.iter().any(|name| pattern.matches(name)) {
+                    matched[offset + i] = true;
+                    any = true;
+                }
+            }
+            any
+        };
+
+        let included = matches(&include, 0) || include.is_empty();
+        let excluded = matches(&exclude, include.len());
+        visible.push(included && !excluded);
+    });
+
+    let unmatched: Vec<&str> = visibility
+        .include
+        .iter()
+        .chain(visibility.exclude.iter())
+        .zip(matched)
+        .filter(|(_, matched)| !matched)
+        .map(|(pattern, _)| pattern.as_str())
+        .collect();
+    if !unmatched.is_empty() {
+        return Err(Error::NodeNotFound(unmatched.join(", ")));
+    }
+
+    Ok(visible)
+}
+
+/// Calls `f` for every part of a `three_d::Model` loaded from the document, in the order of the
+/// parts, with its node and the names of the node, its ancestors and its mesh.
+/// `three_d_asset` creates a part for every primitive with positions of the first scene,
+/// depth first, and drops nodes with a zero scale together with their children.
+fn visit_parts<'a>(doc: &'a gltf::Document, f: &mut dyn FnMut(&Node<'a>, &[&'a str])) {
+    fn visit<'a>(
+        node: &Node<'a>,
+        names: &mut Vec<&'a str>,
+        f: &mut dyn FnMut(&Node<'a>, &[&'a str]),
+    ) {
+        let matrix: Matrix4<f32> = node.transform().matrix().into();
+        if matrix.determinant() == 0. {
+            return;
+        }
+
+        let depth = names.len();
+        names.extend(node.name());
+        if let Some(mesh) = node.mesh() {
+            let node_names = names.len();
+            names.extend(mesh.name());
+            mesh.primitives()
+                .filter(|primitive| primitive.get(&Semantic::Positions).is_some())
+                .for_each(|_| f(node, names));
+            names.truncate(node_names);
+        }
+
+        node.children().for_each(|child| visit(&child, names, f));
+        names.truncate(depth);
+    }
+
+    if let Some(scene) = doc.scenes().next() {
+        let mut names = vec![];
+        scene.nodes().for_each(|node| visit(&node, &mut names, f));
+    }
+}
+
 fn visit_nodes<T>(
     nodes: iter::Children,
     carry: Transform,
@@ -125,6 +339,113 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn part_names() -> Result<()> {
+        let gltf = load_test_model("testdata/duvet-cover.gltf")?;
+        let names = super::part_names(&gltf.document);
+        assert_eq!(names, vec!["Back", "Front", "Pillow"]);
+        Ok(())
+    }
+
+    #[test]
+    fn part_names_match_loaded_model() -> Result<()> {
+        // the canary's mesh under a parent, a child, a zero scaled node and a second root
+        let bytes = std::fs::read("testdata/canary.glb")?;
+        let mut root = gltf::Gltf::from_slice(&bytes)?.document.into_json();
+        let template = root.nodes[root.scenes[0].nodes[0].value()].clone();
+        let mut push =
+            |name: &str, scale: f32, children: Vec<gltf::json::Index<gltf::json::Node>>| {
+                let mut node = template.clone();
+                node.name = Some(name.to_string());
+                node.scale = Some([scale; 3]);
+                node.children = (!children.is_empty()).then_some(children);
+                root.nodes.push(node);
+                gltf::json::Index::new(root.nodes.len() as u32 - 1)
+            };
+        let child = push("Child", 1., vec![]);
+        let hidden = push("Zero", 0., vec![]);
+        let parent = push("Parent", 1., vec![child, hidden]);
+        let sibling = push("Sibling", 1., vec![]);
+        root.scenes[0].nodes.extend([parent, sibling]);
+
+        let mut glb = gltf::binary::Glb::from_slice(&bytes)?;
+        glb.json = Cow::Owned(root.to_vec()?);
+        let bytes = glb.to_vec()?;
+
+        let mut assets = three_d_asset::io::RawAssets::new();
+        assets.insert("parts.glb", bytes.clone());
+        let model: three_d_asset::Model = assets.deserialize("parts.glb")?;
+        let doc = gltf::Gltf::from_slice(&bytes)?.document;
+
+        let names = super::part_names(&doc);
+        assert_eq!(names.len(), model.geometries.len());
+        assert!(names.ends_with(&["Parent", "Child", "Sibling"].map(String::from)));
+        Ok(())
+    }
+
+    #[test]
+    fn part_visibility() -> Result<()> {
+        let doc = load_test_model("testdata/duvet-cover.gltf")?.document;
+        let visibility = |include: &[&str], exclude: &[&str]| {
+            super::part_visibility(
+                &doc,
+                &Visibility {
+                    include: include.iter().map(|s| s.to_string()).collect(),
+                    exclude: exclude.iter().map(|s| s.to_string()).collect(),
+                },
+            )
+        };
+
+        assert_eq!(visibility(&[], &[])?, vec![true, true, true]);
+        assert_eq!(visibility(&[], &["Pillow"])?, vec![true, true, false]);
+        assert_eq!(
+            visibility(&["Front", "B?ck"], &[])?,
+            vec![true, true, false]
+        );
+        // a pattern matching a node applies to its children
+        assert_eq!(
+            visibility(&["*duvet-cover*"], &["Back"])?,
+            vec![false, true, true]
+        );
+        assert_eq!(
+            visibility(&["Hood*"], &[]).unwrap_err().to_string(),
+            "No node matches: Hood*"
+        );
+        assert!(visibility(&["[Back"], &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn scenes() -> Result<()> {
+        let bytes = std::fs::read("testdata/canary.glb")?;
+        let mut root = gltf::Gltf::from_slice(&bytes)?.document.into_json();
+        let mut second = root.scenes[0].clone();
+        second.name = Some("Second".to_string());
+        root.scenes.push(second);
+        root.scene = None;
+        let doc = gltf::Document::from_json(root)?;
+
+        assert_eq!(scene_index(&doc, None)?, 0);
+        assert_eq!(scene_index(&doc, Some(&"1".parse()?))?, 1);
+        assert_eq!(scene_index(&doc, Some(&"Second".parse()?))?, 1);
+        assert_eq!(
+            scene_index(&doc, Some(&"Missing".parse()?))
+                .unwrap_err()
+                .to_string(),
+            "Scene not found: \"Missing\", the model has scenes: 0 \"canary\", 1 \"Second\""
+        );
+
+        let rewritten = with_first_scene(&bytes, doc, 1)?;
+        let doc = gltf::Gltf::from_slice(&rewritten)?.document;
+        assert_eq!(
+            doc.scenes().next().and_then(|scene| scene.name()),
+            Some("Second")
+        );
+        assert_eq!(doc.default_scene().map(|scene| scene.index()), Some(0));
+        assert!(extract(&doc.scenes().next().unwrap(), get_camera).is_some());
+        Ok(())
+    }
+
     fn load_test_model(path: &str) -> Result<gltf::Gltf> {
         let content = std::fs::read(path)?;
         Ok(gltf::Gltf::from_slice(content.as_slice())?)
@@ -1,10 +1,23 @@
+use std::io::Cursor;
+
 use image::DynamicImage;
 use three_d_asset::{Texture2D, TextureData};
 
 use crate::error::Error;
+use crate::limits::Limits;
+
+/// Decodes the image, its size is checked against `limits` before any pixels are decoded.
+pub fn decode(bytes: &[u8], limits: &Limits) -> anyhow::Result<DynamicImage> {
+    let (width, height) = image::io::Reader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_dimensions()?;
+    limits.check_texture(width, height)?;
 
-pub fn decode_img(bytes: &[u8]) -> anyhow::Result<Texture2D> {
-    let img = image::load_from_memory(bytes)?;
+    Ok(image::load_from_memory(bytes)?)
+}
+
+pub fn decode_img(bytes: &[u8], limits: &Limits) -> anyhow::Result<Texture2D> {
+    let img = decode(bytes, limits)?;
 
     let width = img.width();
     let height = img.height();
@@ -40,9 +53,23 @@ pub fn decode_img(bytes: &[u8]) -> anyhow::Result<Texture2D> {
     })
 }
 
-pub async fn download_img(url: String) -> anyhow::Result<Texture2D> {
+pub async fn download_img(url: String, limits: Limits) -> anyhow::Result<Texture2D> {
+    decode_img(&download(url).await?, &limits)
+}
+
+/// Rejects image locations of a request that are not `http(s)://` urls,
+/// `download` would read any other location from the local disk.
+pub fn check_request_url(url: &str) -> Result<(), Error> {
+    match url.starts_with("http://") || url.starts_with("https://") {
+        true => Ok(()),
+        false => Err(Error::ForbiddenImageLocation(url.to_string())),
+    }
+}
+
+/// Reads the image from a local path or downloads it when `url` is remote.
+pub async fn download(url: String) -> anyhow::Result<Vec<u8>> {
     if !url.starts_with("http") {
-        return decode_img(std::fs::read(url)?.as_slice());
+        return Ok(std::fs::read(url)?);
     }
 
     let response = reqwest::get(url).await?;
@@ -54,8 +81,7 @@ pub async fn download_img(url: String) -> anyhow::Result<Texture2D> {
         .into());
     }
 
-    let bytes = response.bytes().await?;
-    decode_img(&bytes)
+    Ok(response.bytes().await?.to_vec())
 }
 
 #[cfg(test)]
@@ -67,7 +93,36 @@ mod tests {
         for ext in ["png", "jpg", "webp"] {
             let image_path = format!("testdata/test.{}", ext);
             let bytes = std::fs::read(&image_path).unwrap();
-            let _ = decode_img(&bytes).unwrap();
+            let _ = decode_img(&bytes, &Limits::default()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_decode_img_limits() {
+        let bytes = std::fs::read("testdata/test.png").unwrap();
+        let limits = Limits {
+            max_texture_pixels: 1,
+            ..Default::default()
+        };
+
+        let error = decode_img(&bytes, &limits).unwrap_err();
+        assert!(error.downcast_ref::<crate::limits::LimitError>().is_some());
+    }
+
+    #[test]
+    fn test_check_request_url() {
+        assert!(check_request_url("https://example.com/mask.png").is_ok());
+        assert!(check_request_url("http://example.com/mask.png").is_ok());
+        for url in [
+            "/etc/passwd",
+            "testdata/test.png",
+            "httpfoo",
+            "file:///etc/passwd",
+        ] {
+            assert!(matches!(
+                check_request_url(url),
+                Err(Error::ForbiddenImageLocation(_))
+            ));
         }
     }
 }
@@ -0,0 +1,370 @@
+use std::fmt;
+use std::path::Path;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use clap::{Arg, ArgMatches, Command};
+use gltf::camera::Projection;
+use gltf::mesh::Mode;
+use gltf::mesh::Semantic;
+use serde::Serialize;
+
+use crate::object::Transform;
+
+pub struct Inspect {}
+
+#[async_trait]
+impl crate::Subcommand for Inspect {
+    fn get_subcommand(&self) -> Command {
+        Command::new("inspect")
+            .arg(Arg::new("input").required(true).long_help("glb/gltf file"))
+            .arg(
+                Arg::new("json")
+                    .long("json")
+                    .action(clap::ArgAction::SetTrue)
+                    .long_help("print the scene tree as json"),
+            )
+            .about("Print the scene tree of a model with transforms, cameras, meshes and materials")
+    }
+
+    async fn run(&self, matches: &ArgMatches) -> Result<()> {
+        let input = matches.get_one::<String>("input").unwrap();
+        let document = inspect(Path::new(input))?;
+
+        match matches.get_flag("json") {
+            true => println!("{}", serde_json::to_string_pretty(&document)?),
+            false => print!("{}", document),
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct Document {
+    pub default_scene: Option<usize>,
+    pub scenes: Vec<Scene>,
+}
+
+#[derive(Serialize)]
+pub struct Scene {
+    pub index: usize,
+    pub name: Option<String>,
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Serialize)]
+pub struct Node {
+    pub index: usize,
+    pub name: Option<String>,
+    pub local: Decomposed,
+    pub world: Decomposed,
+    pub camera: Option<Camera>,
+    pub mesh: Option<Mesh>,
+    pub children: Vec<Node>,
+}
+
+/// A transform split like `Transform::decomposed`, with the rotation also as euler angles in degrees.
+#[derive(Serialize)]
+pub struct Decomposed {
+    pub translation: [f32; 3],
+    pub rotation: [f32; 4],
+    pub euler_degrees: [f32; 3],
+    pub scale: [f32; 3],
+}
+
+#[derive(Serialize)]
+pub struct Camera {
+    pub index: usize,
+    pub name: Option<String>,
+    pub projection: &'static str,
+    pub yfov: Option<f32>,
+    pub aspect_ratio: Option<f32>,
+    pub znear: f32,
+    pub zfar: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct Mesh {
+    pub index: usize,
+    pub name: Option<String>,
+    pub primitives: Vec<Primitive>,
+}
+
+#[derive(Serialize)]
+pub struct Primitive {
+    pub mode: String,
+    pub vertices: Option<usize>,
+    /// Indices of the `TEXCOORD_n` attributes.
+    pub uv_sets: Vec<u32>,
+    pub material: Material,
+}
+
+#[derive(Serialize)]
+pub struct Material {
+    /// `None` for the default material.
+    pub index: Option<usize>,
+    pub name: Option<String>,
+    pub base_color: [f32; 4],
+    /// UV set of the base color texture.
+    pub base_color_texture: Option<u32>,
+}
+
+/// Reads the scene tree of every scene, buffers are not loaded.
+pub fn inspect(path: &Path) -> Result<Document> {
+    let gltf = gltf::Gltf::open(path)?;
+    Ok(document(&gltf.document))
+}
+
+pub fn document(doc: &gltf::Document) -> Document {
+    let identity = Transform {
+        matrix: nalgebra::Matrix4::identity(),
+    };
+
+    Document {
+        default_scene: doc.default_scene().map(|scene| scene.index()),
+        scenes: doc
+            .scenes()
+            .map(|scene| Scene {
+                index: scene.index(),
+                name: scene.name().map(String::from),
+                nodes: scene.nodes().map(|node| visit(&node, identity)).collect(),
+            })
+            .collect(),
+    }
+}
+
+fn visit(node: &gltf::Node, parent: Transform) -> Node {
+    let local = Transform::from(node.transform());
+    let world = parent * local;
+
+    Node {
+        index: node.index(),
+        name: node.name().map(String::from),
+        local: decompose(&local),
+        world: decompose(&world),
+        camera: node.camera().map(|camera| camera_info(&camera)),
+        mesh: node.mesh().map(|mesh| Mesh {
+            index: mesh.index(),
+            name: mesh.name().map(String::from),
+            primitives: mesh.primitives().map(|p| primitive_info(&p)).collect(),
+        }),
+        children: node.children().map(|child| visit(&child, world)).collect(),
+    }
+}
+
+fn decompose(transform: &Transform) -> Decomposed {
+    let (_, rotation, scale) = transform.decomposed();
+    let position = transform.position();
+    let (x, y, z) = transform.rotation().euler_angles();
+
+    Decomposed {
+        translation: [position.x, position.y, position.z],
+        rotation,
+        euler_degrees: [x.to_degrees(), y.to_degrees(), z.to_degrees()],
+        scale,
+    }
+}
+
+fn camera_info(camera: &gltf::Camera) -> Camera {
+    let (projection, yfov, aspect_ratio, znear, zfar) = match camera.projection() {
+        Projection::Perspective(p) => (
+            "perspective",
+            Some(p.yfov()),
+            p.aspect_ratio(),
+            p.znear(),
+            p.zfar(),
+        ),
+        Projection::Orthographic(o) => ("orthographic", None, None, o.znear(), Some(o.zfar())),
+    };
+
+    Camera {
+        index: camera.index(),
+        name: camera.name().map(String::from),
+        projection,
+        yfov,
+        aspect_ratio,
+        znear,
+        zfar,
+    }
+}
+
+fn primitive_info(primitive: &gltf::Primitive) -> Primitive {
+    let mut uv_sets: Vec<u32> = primitive
+        .attributes()
+        .filter_map(|(semantic, _)| match semantic {
+            Semantic::TexCoords(set) => Some(set),
+            _ => None,
+        })
+        .collect();
+    uv_sets.sort();
+
+    let material = primitive.material();
+    let pbr = material.pbr_metallic_roughness();
+
+    Primitive {
+        mode: mode_name(primitive.mode()).to_string(),
+        vertices: primitive
+            .get(&Semantic::Positions)
+            .map(|accessor| accessor.count()),
+        uv_sets,
+        material: Material {
+            index: material.index(),
+            name: material.name().map(String::from),
+            base_color: pbr.base_color_factor(),
+            base_color_texture: pbr.base_color_texture().map(|info| info.tex_coord()),
+        },
+    }
+}
+
+fn mode_name(mode: Mode) -> &'static str {
+    match mode {
+        Mode::Points => "points",
+        Mode::Lines => "lines",
+        Mode::LineLoop => "line loop",
+        Mode::LineStrip => "line strip",
+        Mode::Triangles => "triangles",
+        Mode::TriangleStrip => "triangle strip",
+        Mode::TriangleFan => "triangle fan",
+    }
+}
+
+fn label(index: usize, name: &Option<String>) -> String {
+    match name {
+        Some(name) => format!("{} \"{}\"", index, name),
+        None => index.to_string(),
+    }
+}
+
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for scene in &self.scenes {
+            let default = match self.default_scene == Some(scene.index) {
+                true => " (default)",
+                false => "",
+            };
+            writeln!(f, "scene {}{}", label(scene.index, &scene.name), default)?;
+            for node in &scene.nodes {
+                node.write(f, 1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Node {
+    fn write(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        let indent = "  ".repeat(depth);
+        writeln!(f, "{}node {}", indent, label(self.index, &self.name))?;
+        writeln!(f, "{}  local {}", indent, self.local)?;
+        writeln!(f, "{}  world {}", indent, self.world)?;
+
+        if let Some(camera) = &self.camera {
+            write!(
+                f,
+                "{}  camera {} {}",
+                indent,
+                label(camera.index, &camera.name),
+                camera.projection
+            )?;
+            if let Some(yfov) = camera.yfov {
+                write!(f, ", yfov {:.1}°", yfov.to_degrees())?;
+            }
+            if let Some(aspect_ratio) = camera.aspect_ratio {
+                write!(f, ", aspect ratio {:.3}", aspect_ratio)?;
+            }
+            write!(f, ", znear {}", camera.znear)?;
+            if let Some(zfar) = camera.zfar {
+                write!(f, ", zfar {}", zfar)?;
+            }
+            writeln!(f)?;
+        }
+
+        if let Some(mesh) = &self.mesh {
+            writeln!(f, "{}  mesh {}", indent, label(mesh.index, &mesh.name))?;
+            for (i, primitive) in mesh.primitives.iter().enumerate() {
+                let material = match primitive.material.index {
+                    Some(index) => label(index, &primitive.material.name),
+                    None => "default".to_string(),
+                };
+                writeln!(
+                    f,
+                    "{}    primitive {} {}, {} vertices, uv sets {:?}, material {}, base color {:?}{}",
+                    indent,
+                    i,
+                    primitive.mode,
+                    primitive.vertices.unwrap_or_default(),
+                    primitive.uv_sets,
+                    material,
+                    primitive.material.base_color,
+                    match primitive.material.base_color_texture {
+                        Some(set) => format!(", texture on uv set {}", set),
+                        None => String::new(),
+                    }
+                )?;
+            }
+        }
+
+        for child in &self.children {
+            child.write(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Decomposed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "translation {:?}, rotation {:?}°, scale {:?}",
+            self.translation, self.euler_degrees, self.scale
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inspect() -> Result<()> {
+        let document = inspect(Path::new("testdata/duvet-cover.gltf"))?;
+        assert_eq!(document.default_scene, Some(0));
+
+        let mut names = vec![];
+        fn collect(node: &Node, names: &mut Vec<String>) {
+            if node.mesh.is_some() {
+                names.push(node.name.clone().unwrap_or_default());
+            }
+            node.children.iter().for_each(|child| collect(child, names));
+        }
+        document.scenes[0]
+            .nodes
+            .iter()
+            .for_each(|node| collect(node, &mut names));
+        assert_eq!(names, vec!["Back", "Front", "Pillow"]);
+
+        let text = document.to_string();
+        assert!(text.starts_with("scene 0"));
+        assert!(text.contains("node"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_world_transform() {
+        let parent = Transform::from(gltf::scene::Transform::Decomposed {
+            translation: [1., 2., 3.],
+            rotation: [0., 0., 0., 1.],
+            scale: [2., 2., 2.],
+        });
+        let child = Transform::from(gltf::scene::Transform::Decomposed {
+            translation: [1., 0., 0.],
+            rotation: [0., 0., 0., 1.],
+            scale: [1., 1., 1.],
+        });
+
+        let world = decompose(&(parent * child));
+        assert_eq!(world.translation, [3., 2., 3.]);
+        assert_eq!(world.scale, [2., 2., 2.]);
+    }
+}
@@ -1,17 +1,30 @@
 use async_trait::async_trait;
 use clap::Command;
 
+pub mod antialias;
+pub mod background;
 pub mod collect;
+pub mod color;
+pub mod composite;
+pub mod config_check;
 pub mod download;
 pub mod error;
 pub mod fbx2gltf;
 pub mod gltf;
 pub mod img;
+pub mod inspect;
+pub mod limits;
+pub mod manifest;
+pub mod mockup;
 pub mod model;
 pub mod object;
+pub mod output;
 pub mod render;
 pub mod render_file;
 pub mod server;
+pub mod shadow;
+pub mod storage;
+pub mod validate;
 
 #[async_trait]
 pub trait Subcommand {
@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Upper bounds of requested and decoded image sizes, they keep a single request
+/// from allocating more memory than the pod has.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct Limits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Maximum number of pixels rendered for a request, including supersampling.
+    pub max_render_pixels: u64,
+    /// Maximum number of pixels of a single decoded texture.
+    pub max_texture_pixels: u64,
+    /// Maximum number of texture sets of a `/render-batch` request.
+    pub max_texture_sets: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_width: 4096,
+            max_height: 4096,
+            max_render_pixels: 64 * 1024 * 1024,
+            max_texture_pixels: 64 * 1024 * 1024,
+            max_texture_sets: 256,
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum LimitError {
+    #[error("Invalid size: {width}x{height}")]
+    InvalidSize { width: u32, height: u32 },
+
+    #[error("Requested size {width}x{height} exceeds the maximum of {max_width}x{max_height}")]
+    SizeTooLarge {
+        width: u32,
+        height: u32,
+        max_width: u32,
+        max_height: u32,
+    },
+
+    #[error("Render of {pixels} pixels exceeds the maximum of {max_pixels}")]
+    RenderTooLarge { pixels: u64, max_pixels: u64 },
+
+    #[error("Texture of {width}x{height} exceeds the maximum of {max_pixels} pixels")]
+    TextureTooLarge {
+        width: u32,
+        height: u32,
+        max_pixels: u64,
+    },
+
+    #[error("{count} texture sets exceed the maximum of {max_count}")]
+    TooManyTextureSets { count: usize, max_count: usize },
+}
+
+impl Limits {
+    /// Checks the requested output size and the size it is rendered at with `supersample`.
+    pub fn check_output(
+        &self,
+        width: u32,
+        height: u32,
+        supersample: u32,
+    ) -> Result<(), LimitError> {
+        if width == 0 || height == 0 {
+            return Err(LimitError::InvalidSize { width, height });
+        }
+
+        if width > self.max_width || height > self.max_height {
+            return Err(LimitError::SizeTooLarge {
+                width,
+                height,
+                max_width: self.max_width,
+                max_height: self.max_height,
+            });
+        }
+
+        let factor = supersample.max(1) as u64;
+        let pixels = width as u64 * height as u64 * factor * factor;
+        if pixels > self.max_render_pixels {
+            return Err(LimitError::RenderTooLarge {
+                pixels,
+                max_pixels: self.max_render_pixels,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn check_texture(&self, width: u32, height: u32) -> Result<(), LimitError> {
+        if width as u64 * height as u64 > self.max_texture_pixels {
+            return Err(LimitError::TextureTooLarge {
+                width,
+                height,
+                max_pixels: self.max_texture_pixels,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn check_texture_sets(&self, count: usize) -> Result<(), LimitError> {
+        if count > self.max_texture_sets {
+            return Err(LimitError::TooManyTextureSets {
+                count,
+                max_count: self.max_texture_sets,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_output() {
+        let limits = Limits {
+            max_width: 100,
+            max_height: 50,
+            max_render_pixels: 10_000,
+            max_texture_pixels: 100,
+            max_texture_sets: 10,
+        };
+
+        assert!(limits.check_output(100, 50, 1).is_ok());
+        assert_eq!(
+            limits.check_output(0, 50, 1),
+            Err(LimitError::InvalidSize {
+                width: 0,
+                height: 50
+            })
+        );
+        assert!(matches!(
+            limits.check_output(101, 50, 1),
+            Err(LimitError::SizeTooLarge { .. })
+        ));
+        assert_eq!(
+            limits.check_output(100, 50, 2),
+            Err(LimitError::RenderTooLarge {
+                pixels: 20_000,
+                max_pixels: 10_000
+            })
+        );
+    }
+
+    #[test]
+    fn test_check_texture() {
+        let limits = Limits {
+            max_texture_pixels: 100,
+            ..Default::default()
+        };
+
+        assert!(limits.check_texture(10, 10).is_ok());
+        assert!(limits.check_texture(10, 11).is_err());
+    }
+
+    #[test]
+    fn test_check_texture_sets() {
+        let limits = Limits {
+            max_texture_sets: 2,
+            ..Default::default()
+        };
+
+        assert!(limits.check_texture_sets(2).is_ok());
+        assert_eq!(
+            limits.check_texture_sets(3),
+            Err(LimitError::TooManyTextureSets {
+                count: 3,
+                max_count: 2
+            })
+        );
+    }
+}
@@ -2,13 +2,23 @@ use std::path::Path;
 
 use clap::{Arg, Command};
 
-use gimme_3d::{collect, download, fbx2gltf, render_file, server, Subcommand};
+use gimme_3d::{
+    antialias, collect, color::Color, config_check, download, fbx2gltf, gltf, inspect, output,
+    render, render_file, server, shadow, validate, Subcommand,
+};
 
 #[tokio::main]
 async fn main() {
     let mut root = Command::new("preview")
         .subcommand(
-            Command::new("serve").about("Start http server (using config.toml for configuration)"),
+            Command::new("serve")
+                .arg(
+                    Arg::new("config")
+                        .long("config")
+                        .default_value("config.toml")
+                        .long_help("path to the config file, GIMME3D_* variables override it"),
+                )
+                .about("Start http server (using config.toml for configuration)"),
         )
         .subcommand(
             Command::new("render")
@@ -23,13 +33,76 @@ async fn main() {
                         .long_help("output directory, will be created if not present"),
                 )
                 .arg(Arg::new("texture_url").long_help("texture url to be used, local or remote"))
+                .arg(
+                    Arg::new("quality")
+                        .long("quality")
+                        .value_parser(clap::value_parser!(u8).range(1..=100))
+                        .long_help("webp quality (1-100), lossless when not set"),
+                )
+                .arg(
+                    Arg::new("alpha_quality")
+                        .long("alpha-quality")
+                        .value_parser(clap::value_parser!(u8).range(1..=100))
+                        .long_help("webp alpha channel quality (1-100) of lossy output"),
+                )
+                .arg(
+                    Arg::new("supersample")
+                        .long("supersample")
+                        .value_parser(clap::value_parser!(u32).range(1..=8))
+                        .long_help("render this many times larger before downsampling (default 2)"),
+                )
+                .arg(
+                    Arg::new("filter")
+                        .long("filter")
+                        .value_parser(["box", "triangle", "lanczos3"])
+                        .long_help("downsampling filter (default box)"),
+                )
+                .arg(
+                    Arg::new("msaa")
+                        .long("msaa")
+                        .value_parser(parse_msaa)
+                        .long_help(
+                            "number of GPU multisampling samples, a power of two up to 16 (default off)",
+                        ),
+                )
+                .arg(
+                    Arg::new("contact_shadow")
+                        .long("contact-shadow")
+                        .action(clap::ArgAction::SetTrue)
+                        .long_help("add a soft shadow on the ground beneath the model"),
+                )
+                .arg(Arg::new("scene").long("scene").long_help(
+                    "scene by index or name, the default scene or else the first one by default",
+                ))
+                .arg(
+                    Arg::new("include")
+                        .long("include")
+                        .action(clap::ArgAction::Append)
+                        .long_help("render only nodes or meshes matching this name or glob, can be repeated"),
+                )
+                .arg(
+                    Arg::new("exclude")
+                        .long("exclude")
+                        .action(clap::ArgAction::Append)
+                        .long_help("do not render nodes or meshes matching this name or glob, can be repeated"),
+                )
+                .arg(
+                    Arg::new("color")
+                        .long("color")
+                        .action(clap::ArgAction::Append)
+                        .value_parser(parse_color)
+                        .long_help("render a mesh node with a color instead of a texture, <name>=<#rrggbb or #rrggbbaa>, can be repeated"),
+                )
                 .about("Render a single glb/gltf file or directory containing multiple"),
         );
 
     let debug_components: Vec<Box<dyn Subcommand>> = vec![
         Box::new(download::Download {}),
+        Box::new(config_check::ConfigCheck {}),
         Box::new(collect::Collect {}),
         Box::new(fbx2gltf::Fbx2Gltf {}),
+        Box::new(validate::Validate {}),
+        Box::new(inspect::Inspect {}),
     ];
 
     for component in &debug_components {
@@ -37,30 +110,114 @@ async fn main() {
     }
 
     match root.get_matches().subcommand() {
-        Some(("serve", _)) => {
-            server::run().await;
+        Some(("serve", submatches)) => {
+            let config_path = submatches.get_one::<String>("config").unwrap();
+            if let Err(e) = server::run(config_path).await {
+                eprintln!("Error: {:#}", e);
+                std::process::exit(1);
+            }
         }
         Some(("render", submatches)) => {
             let context = three_d::HeadlessContext::new().unwrap();
             let input = submatches.get_one::<String>("input").unwrap();
             let results = submatches.get_one::<String>("results").unwrap();
             let texture_url = submatches.get_one::<String>("texture_url");
+            let quality = submatches.get_one::<u8>("quality").copied();
+            let options = output::Options {
+                quality,
+                alpha_quality: submatches.get_one::<u8>("alpha_quality").copied(),
+                lossless: quality.is_none(),
+            };
+            let patterns = |name: &str| -> Vec<String> {
+                submatches
+                    .get_many::<String>(name)
+                    .map(|patterns| patterns.cloned().collect())
+                    .unwrap_or_default()
+            };
+            let defaults = antialias::Settings::default();
+            let antialias = antialias::Settings {
+                filter: submatches
+                    .get_one::<String>("filter")
+                    .map(|filter| filter.parse().unwrap())
+                    .unwrap_or(defaults.filter),
+                supersample: submatches
+                    .get_one::<u32>("supersample")
+                    .copied()
+                    .unwrap_or(defaults.supersample),
+                msaa: submatches
+                    .get_one::<u8>("msaa")
+                    .copied()
+                    .unwrap_or(defaults.msaa),
+            };
+            let render_options = render::Options {
+                antialias,
+                contact_shadow: submatches
+                    .get_flag("contact_shadow")
+                    .then(shadow::ContactShadow::default),
+                scene: submatches
+                    .get_one::<String>("scene")
+                    .and_then(|scene| scene.parse().ok()),
+                visibility: gltf::Visibility {
+                    include: patterns("include"),
+                    exclude: patterns("exclude"),
+                },
+                colors: submatches
+                    .get_many::<(String, Color)>("color")
+                    .map(|colors| colors.cloned().collect())
+                    .unwrap_or_default(),
+            };
 
             let input_path = Path::new(input);
 
             if input_path.is_dir() {
-                render_file::run_multiple(input, results, &context, &texture_url).await;
+                render_file::run_multiple(
+                    input,
+                    results,
+                    &context,
+                    &texture_url,
+                    &options,
+                    &render_options,
+                )
+                .await;
             } else {
-                render_file::run(input, results, &context, &texture_url).await;
+                render_file::run(
+                    input,
+                    results,
+                    &context,
+                    &texture_url,
+                    &options,
+                    &render_options,
+                )
+                .await;
             }
         }
         Some((subcommand, submatches)) => {
             for component in &debug_components {
                 if component.get_subcommand().get_name() == subcommand {
-                    component.run(submatches).await.unwrap();
+                    if let Err(e) = component.run(submatches).await {
+                        eprintln!("Error: {:#}", e);
+                        std::process::exit(1);
+                    }
                 }
             }
         }
         None => {}
     }
 }
+
+/// Parses `render --msaa`, see `antialias::check_msaa`.
+fn parse_msaa(value: &str) -> Result<u8, String> {
+    let msaa = value
+        .parse()
+        .map_err(|e: std::num::ParseIntError| e.to_string())?;
+    antialias::check_msaa(msaa).map_err(|e| e.to_string())
+}
+
+/// Parses `<name>=<color>` of `render --color`.
+fn parse_color(value: &str) -> Result<(String, Color), String> {
+    let (name, color) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected <name>=<color>, got {}", value))?;
+    let color = color.parse().map_err(|e: anyhow::Error| e.to_string())?;
+    Ok((name.to_string(), color))
+}
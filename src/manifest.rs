@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::mockup;
+
+/// Extensions of manifest sidecars, in the order they are looked up.
+const EXTENSIONS: [&str; 2] = ["toml", "json"];
+
+/// Everything about a product besides its geometry, read from a sidecar next to the glb
+/// (`duvet-cover.toml` or `duvet-cover.json` for `duvet-cover.glb`).
+/// Relative paths are relative to the directory of the glb.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    /// Human readable name of the product.
+    pub name: Option<String>,
+    /// Photo the render is blended onto when a request has no mask.
+    pub mask: Option<String>,
+    /// Name of the camera node, the first camera of the scene by default.
+    pub camera: Option<String>,
+    /// Supersample factor when a request does not set one.
+    pub supersample: Option<u32>,
+    /// Textures used when a request has none.
+    pub default_textures: Vec<String>,
+    /// Index of the texture of a mesh by its node name, other meshes get the textures in order.
+    pub textures: HashMap<String, usize>,
+    /// Shading and displacement maps of the mask, they win over `[models.mockups]`.
+    pub mockup: Option<mockup::Maps>,
+    /// Directory of the glb.
+    #[serde(skip)]
+    pub dir: PathBuf,
+}
+
+impl Manifest {
+    /// Loads the sidecar of the model, if it has one.
+    pub fn load(model_path: &Path) -> Result<Option<Self>> {
+        let Some(path) = sidecar(model_path) else {
+            return Ok(None);
+        };
+
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading manifest {}", path.display()))?;
+        let mut manifest = Self::parse(&content, &path)
+            .with_context(|| format!("parsing manifest {}", path.display()))?;
+        manifest.dir = model_path.parent().unwrap_or(Path::new("")).to_path_buf();
+
+        Ok(Some(manifest))
+    }
+
+    fn parse(content: &str, path: &Path) -> Result<Self> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Ok(serde_json::from_str(content)?),
+            _ => Ok(toml::from_str(content)?),
+        }
+    }
+
+    /// Resolves a path of the manifest, urls and absolute paths are kept as they are.
+    pub fn resolve(&self, path: &str) -> String {
+        if path.starts_with("http") || Path::new(path).is_absolute() {
+            return path.to_string();
+        }
+
+        self.dir.join(path).to_string_lossy().to_string()
+    }
+
+    pub fn default_texture_urls(&self) -> Vec<String> {
+        self.default_textures
+            .iter()
+            .map(|path| self.resolve(path))
+            .collect()
+    }
+
+    /// Texture of the mesh at `position` named `name`, before wrapping around the textures.
+    pub fn texture_index(&self, name: &str, position: usize) -> usize {
+        self.textures.get(name).copied().unwrap_or(position)
+    }
+}
+
+/// The sidecar file of a model, if there is one.
+pub fn sidecar(model_path: &Path) -> Option<PathBuf> {
+    EXTENSIONS
+        .iter()
+        .map(|extension| model_path.with_extension(extension))
+        .find(|path| path.is_file())
+}
+
+/// File name of the glb of a product, the id of a product is the stem of its glb
+/// in the local model directory.
+pub fn product_model(local_model_dir: &str, id: &str) -> Result<String> {
+    // the id must not leave the model directory
+    let mut components = Path::new(id).components();
+    let is_file_name = matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    );
+
+    let file_name = format!("{}.glb", id);
+    if !is_file_name || !Path::new(local_model_dir).join(&file_name).is_file() {
+        return Err(Error::UnknownProduct(id.to_string()).into());
+    }
+
+    Ok(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let toml = r#"
+            name = "Duvet cover"
+            mask = "duvet-cover.webp"
+            camera = "Camera"
+            supersample = 3
+            default_textures = ["canvas.png"]
+
+            [textures]
+            Front = 0
+            Back = 1
+
+            [mockup]
+            shading_map = "duvet-cover.shading.png"
+        "#;
+        let manifest = Manifest::parse(toml, Path::new("duvet-cover.toml"))?;
+        assert_eq!(manifest.camera, Some("Camera".to_string()));
+        assert_eq!(manifest.supersample, Some(3));
+        assert_eq!(manifest.texture_index("Back", 0), 1);
+        assert_eq!(manifest.texture_index("Pillow", 2), 2);
+        assert_eq!(manifest.mockup.unwrap().shading, 1.);
+
+        let json = r#"{"mask": "duvet-cover.webp", "textures": {"Front": 1}}"#;
+        let manifest = Manifest::parse(json, Path::new("duvet-cover.json"))?;
+        assert_eq!(manifest.mask, Some("duvet-cover.webp".to_string()));
+        assert_eq!(manifest.texture_index("Front", 0), 1);
+
+        assert!(Manifest::parse("maks = 'typo.webp'", Path::new("a.toml")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve() {
+        let manifest = Manifest {
+            dir: PathBuf::from("/var/models"),
+            default_textures: vec!["canvas.png".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(manifest.resolve("mask.webp"), "/var/models/mask.webp");
+        assert_eq!(manifest.resolve("/tmp/mask.webp"), "/tmp/mask.webp");
+        assert_eq!(
+            manifest.resolve("https://example.com/mask.webp"),
+            "https://example.com/mask.webp"
+        );
+        assert_eq!(
+            manifest.default_texture_urls(),
+            vec!["/var/models/canvas.png"]
+        );
+    }
+
+    #[test]
+    fn test_product_model() {
+        assert!(product_model("testdata", "canary").is_ok());
+        assert!(matches!(
+            product_model("testdata", "missing")
+                .unwrap_err()
+                .downcast_ref::<Error>(),
+            Some(Error::UnknownProduct(_))
+        ));
+        assert!(product_model("testdata", "../testdata/canary").is_err());
+        assert!(product_model("testdata", "").is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, RgbImage, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+use crate::composite::BlendMode;
+use crate::img;
+use crate::limits::Limits;
+
+/// Maps of a product photo that make a print follow the fabric, stored alongside the glb.
+/// Mid gray (128) is neutral in both maps.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Maps {
+    /// Highlights and shadows of the photo, lighter brightens and darker darkens the render.
+    pub shading_map: Option<String>,
+    /// Shifts the render, red horizontally and green vertically (a gray map shifts along both).
+    pub displacement_map: Option<String>,
+    /// Strength of the shading, 0-1.
+    pub shading: f32,
+    /// Largest shift in pixels of the displacement map.
+    pub displacement: f32,
+}
+
+impl Default for Maps {
+    fn default() -> Self {
+        Self {
+            shading_map: None,
+            displacement_map: None,
+            shading: 1.,
+            displacement: 10.,
+        }
+    }
+}
+
+/// Maps loaded and scaled to the output size.
+#[derive(Default)]
+pub struct Mockup {
+    shading: Option<RgbImage>,
+    displacement: Option<RgbImage>,
+    shading_strength: f32,
+    /// Largest shift in output pixels.
+    displacement_strength: f32,
+}
+
+/// Decoded maps by location, with the modification time and size of local files they were read at.
+/// Maps at urls are never refreshed, they are kept until a restart, so a changed map needs a new url.
+#[derive(Default)]
+pub struct MapCache {
+    maps: Mutex<HashMap<String, CachedMap>>,
+}
+
+/// Modification time and size of a local map (`None` for urls) and the decoded map.
+type CachedMap = (Option<(SystemTime, u64)>, Arc<DynamicImage>);
+
+impl MapCache {
+    pub fn new_arc() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// The decoded map at `location`, a local file is read again once its modification time
+    /// or size changed, like `Cache::file_hash` does.
+    async fn get(&self, location: &str, limits: &Limits) -> Result<Arc<DynamicImage>> {
+        let modified = match location.starts_with("http") {
+            true => None,
+            false => std::fs::metadata(location)
+                .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+                .ok(),
+        };
+        if let Some((at, map)) = self.maps.lock().unwrap().get(location) {
+            if *at == modified {
+                return Ok(map.clone());
+            }
+        }
+
+        let bytes = img::download(location.to_string()).await?;
+        let map =
+            img::decode(&bytes, limits).with_context(|| format!("loading map {}", location))?;
+        let map = Arc::new(map);
+        self.maps
+            .lock()
+            .unwrap()
+            .insert(location.to_string(), (modified, map.clone()));
+
+        Ok(map)
+    }
+}
+
+impl Mockup {
+    /// Loads the maps, relative paths are resolved against `model_dir`.
+    pub async fn load(
+        maps: &Maps,
+        model_dir: &str,
+        width: u32,
+        height: u32,
+        limits: &Limits,
+        cache: &MapCache,
+    ) -> Result<Self> {
+        let shading = match &maps.shading_map {
+            Some(path) => Some(cache.get(&resolve(path, model_dir), limits).await?),
+            None => None,
+        };
+        let displacement = match &maps.displacement_map {
+            Some(path) => Some(cache.get(&resolve(path, model_dir), limits).await?),
+            None => None,
+        };
+
+        // the displacement is given for the map, the output may be scaled down
+        let displacement_strength = match &displacement {
+            Some(map) => maps.displacement * width as f32 / map.width() as f32,
+            None => 0.,
+        };
+
+        let fit = |map: Arc<DynamicImage>| map.resize_exact(width, height, FilterType::Triangle);
+        Ok(Mockup {
+            shading: shading.map(|map| fit(map).to_rgb8()),
+            displacement: displacement.map(|map| fit(map).to_rgb8()),
+            shading_strength: maps.shading.clamp(0., 1.),
+            displacement_strength,
+        })
+    }
+
+    /// Warps and shades the render, it is expected to have the output size.
+    pub fn apply(&self, pixels: DynamicImage) -> DynamicImage {
+        if self.shading.is_none() && self.displacement.is_none() {
+            return pixels;
+        }
+
+        let mut result = pixels.to_rgba8();
+        if let Some(map) = &self.displacement {
+            result = displace(&result, map, self.displacement_strength);
+        }
+        if let Some(map) = &self.shading {
+            shade(&mut result, map, self.shading_strength);
+        }
+
+        result.into()
+    }
+}
+
+impl Maps {
+    /// Locations of the maps, relative paths are resolved against `model_dir`.
+    pub fn locations(&self, model_dir: &str) -> Vec<String> {
+        [&self.shading_map, &self.displacement_map]
+            .into_iter()
+            .flatten()
+            .map(|path| resolve(path, model_dir))
+            .collect()
+    }
+}
+
+fn resolve(path: &str, model_dir: &str) -> String {
+    if path.starts_with("http") || Path::new(path).is_absolute() {
+        path.to_string()
+    } else {
+        Path::new(model_dir)
+            .join(path)
+            .to_string_lossy()
+            .to_string()
+    }
+}
+
+/// Every pixel is taken from where the map points to, by up to `strength` pixels.
+fn displace(pixels: &RgbaImage, map: &RgbImage, strength: f32) -> RgbaImage {
+    let (width, height) = pixels.dimensions();
+    let offset = |value: u8| (value as f32 - 128.) / 127. * strength;
+
+    RgbaImage::from_fn(width, height, |x, y| {
+        let [r, g, _] = map.get_pixel(x, y).0;
+        let source_x = (x as f32 + offset(r)).clamp(0., (width - 1) as f32);
+        let source_y = (y as f32 + offset(g)).clamp(0., (height - 1) as f32);
+
+        imageops::interpolate_bilinear(pixels, source_x, source_y).unwrap_or(Rgba([0, 0, 0, 0]))
+    })
+}
+
+/// Hard light of the map over the render, mixed in by `strength`, the alpha is kept.
+fn shade(pixels: &mut RgbaImage, map: &RgbImage, strength: f32) {
+    for (x, y, pixel) in pixels.enumerate_pixels_mut() {
+        let shading = map.get_pixel(x, y);
+        for i in 0..3 {
+            let color = pixel[i] as f32 / 255.;
+            // 128 is exactly neutral
+            let light = ((shading[i] as f32 - 128.) / 254. + 0.5).clamp(0., 1.);
+            // hard light is overlay with the layers swapped
+            let shaded = BlendMode::Overlay.apply(light, color);
+            let mixed = color + (shaded - color) * strength;
+            pixel[i] = (mixed * 255.).round().clamp(0., 255.) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    fn mockup(shading: Option<RgbImage>, displacement: Option<RgbImage>) -> Mockup {
+        Mockup {
+            shading,
+            displacement,
+            shading_strength: 1.,
+            displacement_strength: 2.,
+        }
+    }
+
+    #[test]
+    fn test_neutral_maps() {
+        let pixels = DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 4, |x, y| {
+            Rgba([(x * 60) as u8, (y * 60) as u8, 100, 255])
+        }));
+        let gray = RgbImage::from_pixel(4, 4, Rgb([128, 128, 128]));
+
+        let result = mockup(Some(gray.clone()), Some(gray)).apply(pixels.clone());
+        assert_eq!(result.to_rgba8(), pixels.to_rgba8());
+    }
+
+    #[test]
+    fn test_shade() {
+        let pixels = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, Rgba([128; 4])));
+
+        let dark = RgbImage::from_pixel(1, 1, Rgb([0, 0, 0]));
+        let result = mockup(Some(dark), None).apply(pixels.clone()).to_rgba8();
+        assert_eq!(result.get_pixel(0, 0), &Rgba([0, 0, 0, 128]));
+
+        let light = RgbImage::from_pixel(1, 1, Rgb([255, 255, 255]));
+        let result = mockup(Some(light), None).apply(pixels).to_rgba8();
+        assert_eq!(result.get_pixel(0, 0), &Rgba([255, 255, 255, 128]));
+    }
+
+    #[test]
+    fn test_displace() {
+        let pixels = DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 1, |x, _| {
+            Rgba([(x * 60) as u8, 0, 0, 255])
+        }));
+        // full red shifts by the whole strength to the right
+        let map = RgbImage::from_pixel(4, 1, Rgb([255, 128, 0]));
+
+        let result = mockup(None, Some(map)).apply(pixels).to_rgba8();
+        assert_eq!(result.get_pixel(0, 0)[0], 120);
+        assert_eq!(result.get_pixel(3, 0)[0], 180);
+    }
+
+    #[tokio::test]
+    async fn test_map_cache() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("gimme-3d-maps-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("shading.png");
+        let location = path.to_string_lossy().to_string();
+        let limits = Limits::default();
+        let cache = MapCache::default();
+
+        RgbImage::from_pixel(2, 2, Rgb([128, 128, 128])).save(&path)?;
+        let first = cache.get(&location, &limits).await?;
+        assert!(Arc::ptr_eq(&first, &cache.get(&location, &limits).await?));
+
+        // a file of another size is read again, even within the resolution of its modification time
+        RgbImage::from_pixel(3, 3, Rgb([128, 128, 128])).save(&path)?;
+        assert_eq!(cache.get(&location, &limits).await?.width(), 3);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
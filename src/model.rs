@@ -4,12 +4,17 @@ use anyhow::{anyhow, Result};
 use three_d_asset::io::RawAssets;
 
 use crate::error::Error;
+use crate::manifest::Manifest;
+use crate::storage;
 
+/// Loads the model and its manifest sidecar, returns them with the path the model was loaded from.
+/// A model missing from `local_model_dir` is read from its storage and cached there.
 pub async fn load(
     model_path: Option<String>,
     local_model_dir: &String,
     model_bytes: Option<Vec<u8>>,
-) -> Result<(RawAssets, String)> {
+    storage: &storage::Settings,
+) -> Result<(RawAssets, String, Option<Manifest>)> {
     let final_model_path;
     let loaded_assets;
 
@@ -18,10 +23,7 @@ pub async fn load(
     }
 
     if let Some(model_bytes) = model_bytes {
-        final_model_path = if model_path.is_none() {
-            "tmp_model.glb".to_string()
-        } else {
-            let model_path = model_path.unwrap();
+        final_model_path = if let Some(model_path) = &model_path {
             Path::new(local_model_dir)
                 .join(
                     Path::new(model_path.as_str())
@@ -31,22 +33,31 @@ pub async fn load(
                 .to_str()
                 .ok_or(anyhow!("model path is not valid utf-8"))?
                 .to_string()
+        } else {
+            "tmp_model.glb".to_string()
         };
 
         // todo
         std::fs::write(final_model_path.clone(), model_bytes.clone())?;
-        loaded_assets = three_d_asset::io::load(&[final_model_path.clone()])?;
+        loaded_assets = three_d_asset::io::load(std::slice::from_ref(&final_model_path))?;
+
+        // an uploaded model of a known product gets the product's manifest
+        let manifest = if model_path.is_some() {
+            Manifest::load(Path::new(&final_model_path))?
+        } else {
+            None
+        };
 
-        return Ok((loaded_assets, final_model_path));
+        return Ok((loaded_assets, final_model_path, manifest));
     }
 
     let model_path = model_path.unwrap();
 
     if let Ok(model_path) = get_local_model(local_model_dir, &model_path.clone()) {
-        loaded_assets = three_d_asset::io::load(&[model_path.clone()])?;
+        loaded_assets = three_d_asset::io::load(std::slice::from_ref(&model_path))?;
         final_model_path = model_path.clone();
     } else {
-        let model_bytes = download(model_path.clone()).await?;
+        let model_bytes = storage::get(&model_path, storage).await?;
         let model_path = Path::new(local_model_dir).join(
             Path::new(model_path.as_str())
                 .file_name()
@@ -54,33 +65,16 @@ pub async fn load(
         );
 
         std::fs::write(model_path.clone(), model_bytes.clone())?;
-        loaded_assets = three_d_asset::io::load(&[model_path.clone()])?;
+        loaded_assets = three_d_asset::io::load(std::slice::from_ref(&model_path))?;
         final_model_path = model_path
             .to_str()
             .ok_or(anyhow!("model path is not valid utf-8"))?
             .to_string();
     }
 
-    Ok((loaded_assets, final_model_path))
-}
-
-pub async fn download(url: String) -> Result<Vec<u8>> {
-    if !url.starts_with("http") {
-        return std::fs::read(url).map_err(|e| e.into());
-    }
-
-    let response = reqwest::get(url).await?;
-
-    if !response.status().is_success() {
-        return Err(Error::ModelDownloadError {
-            status_code: response.status(),
-            message: response.text().await.unwrap_or("".to_owned()),
-        }
-        .into());
-    }
+    let manifest = Manifest::load(Path::new(&final_model_path))?;
 
-    let bytes = response.bytes().await?;
-    Ok(bytes.to_vec())
+    Ok((loaded_assets, final_model_path, manifest))
 }
 
 fn get_local_model(local_dir: &String, path: &String) -> Result<String> {
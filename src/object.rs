@@ -9,6 +9,7 @@ use three_d_asset::Mat4;
 
 #[derive(Debug, Clone)]
 pub struct Camera {
+    pub name: Option<String>,
     pub parent_transform: Transform,
     pub transform: Transform,
     pub aspect_ratio: f32,
@@ -19,6 +20,7 @@ pub struct Camera {
 
 #[derive(Debug)]
 pub struct Mesh {
+    pub name: Option<String>,
     pub parent_transform: Transform,
     pub transform: Transform,
 }
@@ -0,0 +1,215 @@
+use std::io::Cursor;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageOutputFormat, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_JPEG_QUALITY: u8 = 85;
+pub const DEFAULT_AVIF_QUALITY: u8 = 80;
+pub const DEFAULT_WEBP_QUALITY: u8 = 80;
+pub const DEFAULT_WEBP_ALPHA_QUALITY: u8 = 100;
+
+/// AVIF encoding speed, 1 (slowest, smallest) to 10 (fastest).
+const AVIF_SPEED: u8 = 8;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Png,
+    Jpeg,
+    Webp,
+    Avif,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Png => "image/png",
+            Format::Jpeg => "image/jpeg",
+            Format::Webp => "image/webp",
+            Format::Avif => "image/avif",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Jpeg => "jpg",
+            Format::Webp => "webp",
+            Format::Avif => "avif",
+        }
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type {
+            "image/png" => Some(Format::Png),
+            "image/jpeg" | "image/jpg" => Some(Format::Jpeg),
+            "image/webp" => Some(Format::Webp),
+            "image/avif" => Some(Format::Avif),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "png" => Ok(Format::Png),
+            "jpeg" | "jpg" => Ok(Format::Jpeg),
+            "webp" => Ok(Format::Webp),
+            "avif" => Ok(Format::Avif),
+            _ => Err(anyhow!("unsupported output format: {}", s)),
+        }
+    }
+}
+
+/// Encoder settings, qualities are 1-100 and fall back to the format's default when not set.
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Options {
+    pub quality: Option<u8>,
+    /// Quality of the alpha channel (webp only), 100 keeps it lossless.
+    pub alpha_quality: Option<u8>,
+    /// Lossless webp, the qualities are ignored then.
+    pub lossless: bool,
+}
+
+/// Encodes the image, qualities of `options` are used by lossy formats only.
+/// Formats without an alpha channel (jpeg) get the transparent areas filled with white.
+pub fn encode(img: &DynamicImage, format: Format, options: &Options) -> Result<Vec<u8>> {
+    let mut writer = Cursor::new(Vec::new());
+    let quality = options.quality;
+
+    match format {
+        Format::Png => img.write_to(&mut writer, ImageOutputFormat::Png)?,
+        Format::Webp if options.lossless => img.write_to(&mut writer, ImageOutputFormat::WebP)?,
+        Format::Webp => {
+            let quality = quality.unwrap_or(DEFAULT_WEBP_QUALITY).clamp(1, 100);
+            let alpha_quality = options
+                .alpha_quality
+                .unwrap_or(DEFAULT_WEBP_ALPHA_QUALITY)
+                .clamp(1, 100);
+            return encode_lossy_webp(img, quality, alpha_quality);
+        }
+        Format::Jpeg => {
+            let quality = quality.unwrap_or(DEFAULT_JPEG_QUALITY).clamp(1, 100);
+            let flattened = DynamicImage::ImageRgba8(flatten(img, Rgba([255, 255, 255, 255])));
+            JpegEncoder::new_with_quality(&mut writer, quality)
+                .encode_image(&flattened.to_rgb8())?
+        }
+        Format::Avif => {
+            let quality = quality.unwrap_or(DEFAULT_AVIF_QUALITY).clamp(1, 100);
+            return encode_avif(img, quality);
+        }
+    }
+
+    Ok(writer.into_inner())
+}
+
+fn encode_lossy_webp(img: &DynamicImage, quality: u8, alpha_quality: u8) -> Result<Vec<u8>> {
+    let rgba = img.to_rgba8();
+
+    let mut config = webp::WebPConfig::new().map_err(|_| anyhow!("invalid webp config"))?;
+    config.lossless = 0;
+    config.quality = quality as f32;
+    config.alpha_quality = alpha_quality as i32;
+
+    let encoded = webp::Encoder::from_rgba(rgba.as_raw(), rgba.width(), rgba.height())
+        .encode_advanced(&config)
+        .map_err(|e| anyhow!("webp encoding failed: {:?}", e))?;
+
+    Ok(encoded.to_vec())
+}
+
+fn encode_avif(img: &DynamicImage, quality: u8) -> Result<Vec<u8>> {
+    let rgba = img.to_rgba8();
+    let pixels: Vec<ravif::RGBA8> = rgba
+        .chunks_exact(4)
+        .map(|c| ravif::RGBA8::new(c[0], c[1], c[2], c[3]))
+        .collect();
+
+    let encoded = ravif::Encoder::new()
+        .with_quality(quality as f32)
+        .with_alpha_quality(quality as f32)
+        .with_speed(AVIF_SPEED)
+        .encode_rgba(ravif::Img::new(
+            pixels.as_slice(),
+            rgba.width() as usize,
+            rgba.height() as usize,
+        ))?;
+
+    Ok(encoded.avif_file)
+}
+
+/// Composites the image over a solid color, the result is fully opaque.
+pub fn flatten(img: &DynamicImage, color: Rgba<u8>) -> RgbaImage {
+    let mut result = RgbaImage::from_pixel(img.width(), img.height(), color);
+    image::imageops::overlay(&mut result, img, 0, 0);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() -> Result<()> {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 128])));
+
+        let options = Options {
+            quality: Some(50),
+            ..Default::default()
+        };
+
+        for format in [Format::Png, Format::Jpeg, Format::Webp, Format::Avif] {
+            let bytes = encode(&img, format, &options)?;
+            assert!(!bytes.is_empty());
+
+            if format != Format::Avif {
+                let guessed = image::guess_format(&bytes)?;
+                assert_eq!(guessed.to_mime_type(), format.content_type());
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lossy_webp_is_smaller() -> Result<()> {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 64, |x, y| {
+            Rgba([(x * 4) as u8, (y * 4) as u8, ((x * y) % 255) as u8, 255])
+        }));
+
+        let lossless = encode(
+            &img,
+            Format::Webp,
+            &Options {
+                lossless: true,
+                ..Default::default()
+            },
+        )?;
+        let lossy = encode(
+            &img,
+            Format::Webp,
+            &Options {
+                quality: Some(50),
+                ..Default::default()
+            },
+        )?;
+
+        assert_eq!(image::guess_format(&lossy)?, image::ImageFormat::WebP);
+        assert!(lossy.len() < lossless.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, Rgba([0, 0, 0, 0])));
+        let flattened = flatten(&img, Rgba([255, 255, 255, 255]));
+        assert_eq!(flattened.get_pixel(0, 0), &Rgba([255, 255, 255, 255]));
+    }
+}
@@ -1,17 +1,28 @@
-use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Result};
 use image::{DynamicImage, ImageBuffer, Rgba};
 use log::info;
 use nalgebra::Point3;
 use three_d::{
-    vec3, Blend, Camera, ClearState, ColorMaterial, CpuTexture, Cull, DepthTexture2D, Model,
-    RenderTarget, Texture2D, Texture2DRef,
+    vec3, Blend, Camera, ClearState, ColorMaterial, CpuTexture, Cull, DepthTexture2D, Gm, Mesh,
+    Model, Object, RenderTarget, RenderTargetMultisample, Srgba, Texture2D, Texture2DRef,
 };
-use three_d_asset::io::Deserialize;
-use three_d_asset::{radians, Interpolation, Viewport, Wrapping};
+use three_d_asset::io::{Deserialize, RawAssets};
+use three_d_asset::{radians, Interpolation, TextureData, Viewport, Wrapping};
 
+use crate::antialias;
+use crate::color::Color;
 use crate::error::Error;
-use crate::{img, model};
-
+use crate::gltf::{SceneSelector, Visibility};
+use crate::limits::Limits;
+use crate::manifest::Manifest;
+use crate::shadow::{self, ContactShadow};
+use crate::{img, model, storage};
+
+/// Renders the model with textures downloaded from `textures`,
+/// `width` and `height` are the size of the result after antialiasing.
+#[allow(clippy::too_many_arguments)]
 pub async fn render_urls(
     remote_model_path: Option<String>,
     model_bytes: Option<Vec<u8>>,
@@ -19,47 +30,47 @@ pub async fn render_urls(
     context: &three_d::Context,
     width: u32,
     height: u32,
+    options: &Options,
+    limits: &Limits,
     local_model_dir: &String,
+    storage: &storage::Settings,
 ) -> Result<DynamicImage> {
-    let texture_futures = textures
-        .iter()
-        .map(|url| tokio::spawn(img::download_img(url.clone())));
+    let texture_future = tokio::spawn(download_textures(textures, *limits));
 
     let start = std::time::Instant::now();
 
-    let (mut loaded_assets, final_model_path) =
-        model::load(remote_model_path, local_model_dir, model_bytes).await?;
-    let model_vec = Vec::from(
-        loaded_assets
-            .get(final_model_path.clone().as_str())
-            .map_err(Error::AssetLoadingError)?,
-    );
-
-    let gltf = gltf::Gltf::from_slice(model_vec.as_slice()).map_err(Error::GltfParsingError)?;
-    let doc = gltf.document;
-
-    let model =
-        three_d_asset::Model::deserialize(final_model_path.clone().as_str(), &mut loaded_assets)
-            .context("loading model")?;
+    let (model, doc, manifest) = load_model(
+        remote_model_path,
+        model_bytes,
+        local_model_dir,
+        storage,
+        options.scene.as_ref(),
+    )
+    .await?;
 
     info!("Model load: {:?}", std::time::Instant::now() - start);
     let start = std::time::Instant::now();
 
-    let cpu_textures: Vec<_> = futures_util::future::join_all(texture_futures)
-        .await
-        .into_iter()
-        .map(|result| {
-            let mut cpu_texture = result.unwrap().unwrap();
-            cpu_texture.data.to_linear_srgb();
-            cpu_texture
-        })
-        .collect();
+    let mut cpu_textures = texture_future.await??;
+    if cpu_textures.is_empty() {
+        cpu_textures = download_textures(manifest.default_texture_urls(), *limits).await?;
+    }
 
     info!("Textures load: {:?}", std::time::Instant::now() - start);
 
-    render(context, model, cpu_textures, doc, width, height)
+    render(
+        context,
+        model,
+        cpu_textures,
+        doc,
+        &manifest,
+        width,
+        height,
+        options,
+    )
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn render_raw_images(
     model_path: Option<String>,
     model_bytes: Option<Vec<u8>>,
@@ -67,143 +78,399 @@ pub async fn render_raw_images(
     context: &three_d::Context,
     width: u32,
     height: u32,
+    options: &Options,
+    limits: &Limits,
     local_model_path: &String,
+    storage: &storage::Settings,
 ) -> Result<DynamicImage> {
     let start = std::time::Instant::now();
 
-    let cpu_textures = raw_textures
-        .iter()
-        .map(|raw_texture| {
-            let mut cpu_texture = img::decode_img(raw_texture.as_slice()).expect("decoding image");
-            cpu_texture.data.to_linear_srgb();
-            cpu_texture
-        })
-        .collect();
+    let mut cpu_textures = decode_textures(&raw_textures, limits)?;
 
     info!("Textures load: {:?}", std::time::Instant::now() - start);
     let start = std::time::Instant::now();
 
-    let (mut loaded_assets, final_model_path) =
-        model::load(model_path, local_model_path, model_bytes).await?;
+    let (model, doc, manifest) = load_model(
+        model_path,
+        model_bytes,
+        local_model_path,
+        storage,
+        options.scene.as_ref(),
+    )
+    .await?;
+
+    info!("Model load: {:?}", std::time::Instant::now() - start);
+
+    if cpu_textures.is_empty() {
+        cpu_textures = download_textures(manifest.default_texture_urls(), *limits).await?;
+    }
+
+    render(
+        context,
+        model,
+        cpu_textures,
+        doc,
+        &manifest,
+        width,
+        height,
+        options,
+    )
+}
+
+/// Loads the model together with its gltf document, which is needed for cameras and meshes,
+/// and its manifest (empty for a model without one). The scene to render becomes the first
+/// scene of both, see `crate::gltf::scene_index`.
+pub async fn load_model(
+    model_path: Option<String>,
+    model_bytes: Option<Vec<u8>>,
+    local_model_dir: &String,
+    storage: &storage::Settings,
+    scene: Option<&SceneSelector>,
+) -> Result<(three_d_asset::Model, gltf::Document, Manifest)> {
+    let (mut loaded_assets, final_model_path, manifest) =
+        model::load(model_path, local_model_dir, model_bytes, storage).await?;
 
     let model_vec = Vec::from(
         loaded_assets
-            .get(final_model_path.clone())
+            .get(final_model_path.as_str())
             .map_err(Error::AssetLoadingError)?,
     );
 
-    let gltf = gltf::Gltf::from_slice(model_vec.as_slice()).map_err(Error::GltfParsingError)?;
-    let doc = gltf.document;
+    let mut gltf = gltf::Gltf::from_slice(model_vec.as_slice()).map_err(Error::GltfParsingError)?;
 
-    let model = loaded_assets.deserialize(final_model_path.as_str())?;
+    let index = crate::gltf::scene_index(&gltf.document, scene)?;
+    if index != 0 {
+        let model_vec = crate::gltf::with_first_scene(&model_vec, gltf.document, index)
+            .context("selecting scene")?;
+        gltf = gltf::Gltf::from_slice(model_vec.as_slice()).map_err(Error::GltfParsingError)?;
+        loaded_assets.insert(final_model_path.as_str(), model_vec);
+    }
 
-    info!("Model load: {:?}", std::time::Instant::now() - start);
+    let model = three_d_asset::Model::deserialize(final_model_path.as_str(), &mut loaded_assets)
+        .context("loading model")?;
 
-    render(context, model, cpu_textures, doc, width, height)
+    Ok((model, gltf.document, manifest.unwrap_or_default()))
 }
 
+/// Downloads all textures concurrently and converts them to linear srgb.
+pub async fn download_textures(urls: Vec<String>, limits: Limits) -> Result<Vec<CpuTexture>> {
+    let texture_futures = urls
+        .into_iter()
+        .map(|url| tokio::spawn(img::download_img(url, limits)));
+
+    futures_util::future::join_all(texture_futures)
+        .await
+        .into_iter()
+        .map(|result| {
+            let mut cpu_texture = result??;
+            cpu_texture.data.to_linear_srgb();
+            Ok(cpu_texture)
+        })
+        .collect()
+}
+
+pub fn decode_textures(raw_textures: &[Vec<u8>], limits: &Limits) -> Result<Vec<CpuTexture>> {
+    raw_textures
+        .iter()
+        .map(|raw_texture| {
+            let mut cpu_texture =
+                img::decode_img(raw_texture.as_slice(), limits).context("decoding image")?;
+            cpu_texture.data.to_linear_srgb();
+            Ok(cpu_texture)
+        })
+        .collect()
+}
+
+const CANARY_MODEL: &[u8] = include_bytes!("../testdata/canary.glb");
+
+/// Renders a tiny built-in scene (a white quad in front of a camera),
+/// fails if the context does not draw anything.
+pub fn canary(context: &three_d::Context) -> Result<()> {
+    let mut loaded_assets = RawAssets::new();
+    loaded_assets.insert("canary.glb", CANARY_MODEL.to_vec());
+    let model = loaded_assets.deserialize("canary.glb")?;
+
+    let gltf = gltf::Gltf::from_slice(CANARY_MODEL).map_err(Error::GltfParsingError)?;
+    let texture = CpuTexture {
+        data: TextureData::RgbaU8(vec![[255, 255, 255, 255]]),
+        width: 1,
+        height: 1,
+        ..Default::default()
+    };
+
+    let pixels = render(
+        context,
+        model,
+        vec![texture],
+        gltf.document,
+        &Manifest::default(),
+        16,
+        16,
+        &Options::default(),
+    )?
+    .to_rgba8();
+    if pixels.get_pixel(8, 8)[3] == 0 {
+        return Err(anyhow!("canary render is empty"));
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 fn render(
     context: &three_d::Context,
     model: three_d_asset::Model,
     cpu_textures: Vec<CpuTexture>,
     doc: gltf::Document,
+    manifest: &Manifest,
     width: u32,
     height: u32,
+    options: &Options,
 ) -> Result<DynamicImage> {
-    if cpu_textures.is_empty() {
-        return Err(Error::NoTextures.into());
-    }
+    PreparedModel::new(context, &model, &doc, manifest, width, height, options)?
+        .render(context, &cpu_textures)
+}
 
-    let start = std::time::Instant::now();
+/// How a model is rendered, besides its textures.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub antialias: antialias::Settings,
+    pub contact_shadow: Option<ContactShadow>,
+    /// Scene to render, the default scene or else the first one when not set.
+    pub scene: Option<SceneSelector>,
+    /// Nodes to render, all by default.
+    pub visibility: Visibility,
+    /// Colors of mesh nodes by name, these are rendered without a texture.
+    pub colors: BTreeMap<String, Color>,
+}
 
-    let scene = doc.default_scene().ok_or(Error::NoDefaultScene)?;
-    let camera_props =
-        crate::gltf::extract(&scene, crate::gltf::get_camera).ok_or(Error::NoCamera)?;
-    let mesh_props = crate::gltf::extract_all(&scene, crate::gltf::get_mesh);
+/// A model uploaded to the GPU together with its camera,
+/// it can be rendered repeatedly with different textures.
+pub struct PreparedModel {
+    mesh: Model<ColorMaterial>,
+    /// Texture of every part of the mesh, before wrapping around the textures.
+    texture_indices: Vec<usize>,
+    /// Color of every part of the mesh that is rendered without a texture.
+    colors: Vec<Option<Color>>,
+    ground: Option<Gm<Mesh, ColorMaterial>>,
+    camera: Camera,
+    viewport: Viewport,
+    width: u32,
+    height: u32,
+    antialias: antialias::Settings,
+}
 
-    if mesh_props.is_empty() {
-        return Err(Error::NoMesh.into());
+impl PreparedModel {
+    pub fn new(
+        context: &three_d::Context,
+        model: &three_d_asset::Model,
+        doc: &gltf::Document,
+        manifest: &Manifest,
+        width: u32,
+        height: u32,
+        options: &Options,
+    ) -> Result<Self> {
+        let antialias = &options.antialias;
+        // `load_model` moves the selected scene to the front, three_d_asset loads the first scene
+        let scene = doc.scenes().next().ok_or(Error::NoScene)?;
+        let camera_props = match &manifest.camera {
+            Some(name) => crate::gltf::extract_all(&scene, crate::gltf::get_camera)
+                .into_iter()
+                .find(|camera| camera.name.as_ref() == Some(name))
+                .ok_or_else(|| Error::CameraNotFound(name.clone()))?,
+            None => crate::gltf::extract(&scene, crate::gltf::get_camera).ok_or(Error::NoCamera)?,
+        };
+        let mesh_props = crate::gltf::extract_all(&scene, crate::gltf::get_mesh);
+
+        if mesh_props.is_empty() {
+            return Err(Error::NoMesh.into());
+        }
+
+        let mut mesh = Model::<ColorMaterial>::new(context, model).context("creating mesh")?;
+        let part_names = crate::gltf::part_names(doc);
+        let mut texture_indices: Vec<usize> = part_names
+            .iter()
+            .enumerate()
+            .map(|(position, name)| manifest.texture_index(name, position))
+            .collect();
+
+        let unknown: Vec<&str> = options
+            .colors
+            .keys()
+            .filter(|name| !part_names.contains(name))
+            .map(String::as_str)
+            .collect();
+        if !unknown.is_empty() {
+            return Err(Error::NodeNotFound(unknown.join(", ")).into());
+        }
+        let mut colors: Vec<Option<Color>> = part_names
+            .iter()
+            .map(|name| options.colors.get(name).copied())
+            .collect();
+
+        // hidden parts are dropped after assigning textures, so a variant keeps the textures
+        // its parts have with every node visible
+        if !options.visibility.is_empty() {
+            let visible = crate::gltf::part_visibility(doc, &options.visibility)?;
+            let mut parts = visible.iter();
+            mesh.retain(|_| parts.next().copied().unwrap_or(true));
+            let mut parts = visible.iter();
+            texture_indices.retain(|_| parts.next().copied().unwrap_or(true));
+            let mut parts = visible.iter();
+            colors.retain(|_| parts.next().copied().unwrap_or(true));
+
+            if mesh.is_empty() {
+                return Err(Error::NoMesh.into());
+            }
+        }
+        let ground = options
+            .contact_shadow
+            .map(|contact_shadow| shadow::ground(context, &mesh, &contact_shadow))
+            .transpose()
+            .context("creating contact shadow")?;
+
+        let camera_transform = camera_props.parent_transform * camera_props.transform;
+        let point = camera_transform.position();
+
+        let camera_rotation = camera_transform.rotation();
+        let at = camera_rotation.transform_point(&Point3::new(0.0, 0.0, -1.0));
+        let up = camera_rotation.transform_point(&Point3::new(0.0, 1.0, 0.0));
+
+        let (render_width, render_height) = antialias.render_size(width, height);
+        let viewport = Viewport::new_at_origo(render_width, render_height);
+        const FACTOR: f32 = 100.;
+
+        let yfov = camera_props.yfov * (width as f32 / height as f32);
+        // let yfov = 0.356186 * (width as f32 / height as f32);
+
+        let camera = Camera::new_perspective(
+            viewport,
+            vec3(point.x, point.y, point.z),
+            vec3(at.x, at.y, at.z),
+            vec3(up.x, up.y, up.z),
+            radians(yfov),
+            camera_props.znear / FACTOR,
+            camera_props.zfar * FACTOR,
+        );
+
+        Ok(PreparedModel {
+            mesh,
+            texture_indices,
+            colors,
+            ground,
+            camera,
+            viewport,
+            width,
+            height,
+            antialias: *antialias,
+        })
     }
 
-    let mut mesh = Model::<ColorMaterial>::new(context, &model).context("creating mesh")?;
-    let num_textures = cpu_textures.len();
-
-    let mut textures: Vec<&three_d_asset::Texture2D> = vec![];
-    for cpu_texture in cpu_textures.iter() {
-        let texture = &cpu_texture;
-        textures.push(texture);
+    /// Renders the model with textures applied to meshes in order (or as mapped by the manifest),
+    /// wrapping around when there are fewer textures than meshes, meshes with a color get no texture.
+    /// The result is downsampled to the requested size.
+    pub fn render(
+        &mut self,
+        context: &three_d::Context,
+        cpu_textures: &[CpuTexture],
+    ) -> Result<DynamicImage> {
+        let textured = self.mesh.len() > self.colors.iter().flatten().count();
+        if cpu_textures.is_empty() && textured {
+            return Err(Error::NoTextures.into());
+        }
+
+        let start = std::time::Instant::now();
+
+        let num_textures = cpu_textures.len();
+
+        self.mesh.iter_mut().enumerate().for_each(|(pos, m)| {
+            match self.colors.get(pos).copied().flatten() {
+                Some(Color([r, g, b, a])) => {
+                    m.material.color = Srgba::new(r, g, b, a);
+                    m.material.texture = None;
+                }
+                None => {
+                    let index = self.texture_indices.get(pos).copied().unwrap_or(pos);
+                    m.material.texture = Some(Texture2DRef::from_cpu_texture(
+                        context,
+                        &cpu_textures[index % num_textures],
+                    ));
+                }
+            }
+            m.material.is_transparent = true;
+            m.material.render_states.cull = Cull::None;
+            m.material.render_states.blend = Blend::STANDARD_TRANSPARENCY;
+        });
+
+        let viewport = self.viewport;
+
+        let clear_state = ClearState::color_and_depth(0.0, 0.0, 0.0, 0.0, 1.0);
+        let pixels: Vec<[u8; 4]> = if self.antialias.msaa > 0 {
+            let mut resolved = RenderTargetMultisample::<[u8; 4], f32>::new(
+                context,
+                viewport.width,
+                viewport.height,
+                self.antialias.msaa as u32,
+            )
+            .clear(clear_state)
+            .render(&self.camera, self.objects(), &[])
+            .resolve_color();
+
+            let pixels = resolved.as_color_target(None).read();
+            pixels
+        } else {
+            let mut texture = Texture2D::new_empty::<[u8; 4]>(
+                context,
+                viewport.width,
+                viewport.height,
+                Interpolation::Nearest,
+                Interpolation::Nearest,
+                None,
+                Wrapping::ClampToEdge,
+                Wrapping::ClampToEdge,
+            );
+
+            let mut depth_texture = DepthTexture2D::new::<f32>(
+                context,
+                viewport.width,
+                viewport.height,
+                Wrapping::ClampToEdge,
+                Wrapping::ClampToEdge,
+            );
+
+            let pixels = RenderTarget::new(
+                texture.as_color_target(None),
+                depth_texture.as_depth_target(),
+            )
+            .clear(clear_state)
+            .render(&self.camera, self.objects(), &[])
+            .read_color();
+            pixels
+        };
+
+        let img = DynamicImage::ImageRgba8(
+            ImageBuffer::<Rgba<u8>, Vec<u8>>::from_raw(
+                viewport.width,
+                viewport.height,
+                pixels.iter().flat_map(|v| *v).collect::<Vec<_>>(),
+            )
+            .unwrap(),
+        );
+
+        info!("Time render: {:?}", std::time::Instant::now() - start);
+
+        Ok(antialias::downsample(
+            img,
+            self.width,
+            self.height,
+            self.antialias.filter,
+        ))
     }
 
-    mesh.iter_mut().enumerate().for_each(|(pos, m)| {
-        m.material.texture = Some(Texture2DRef::from_cpu_texture(
-            context,
-            &cpu_textures[pos % num_textures],
-        ));
-        m.material.is_transparent = true;
-        m.material.render_states.cull = Cull::None;
-        m.material.render_states.blend = Blend::STANDARD_TRANSPARENCY;
-    });
-
-    let camera_transform = camera_props.parent_transform * camera_props.transform;
-    let point = camera_transform.position();
-
-    let camera_rotation = camera_transform.rotation();
-    let at = camera_rotation.transform_point(&Point3::new(0.0, 0.0, -1.0));
-    let up = camera_rotation.transform_point(&Point3::new(0.0, 1.0, 0.0));
-
-    let viewport = Viewport::new_at_origo(width, height);
-    const FACTOR: f32 = 100.;
-
-    let yfov = camera_props.yfov * (width as f32 / height as f32);
-    // let yfov = 0.356186 * (width as f32 / height as f32);
-
-    let camera = Camera::new_perspective(
-        viewport,
-        vec3(point.x, point.y, point.z),
-        vec3(at.x, at.y, at.z),
-        vec3(up.x, up.y, up.z),
-        radians(yfov),
-        camera_props.znear / FACTOR,
-        camera_props.zfar * FACTOR,
-    );
-
-    let mut texture = Texture2D::new_empty::<[u8; 4]>(
-        context,
-        viewport.width,
-        viewport.height,
-        Interpolation::Nearest,
-        Interpolation::Nearest,
-        None,
-        Wrapping::ClampToEdge,
-        Wrapping::ClampToEdge,
-    );
-
-    let mut depth_texture = DepthTexture2D::new::<f32>(
-        context,
-        viewport.width,
-        viewport.height,
-        Wrapping::ClampToEdge,
-        Wrapping::ClampToEdge,
-    );
-
-    let pixels: Vec<[u8; 4]> = RenderTarget::new(
-        texture.as_color_target(None),
-        depth_texture.as_depth_target(),
-    )
-    .clear(ClearState::color_and_depth(0.0, 0.0, 0.0, 0.0, 1.0))
-    .render(&camera, &mesh, &[])
-    .read_color();
-
-    let img = DynamicImage::ImageRgba8(
-        ImageBuffer::<Rgba<u8>, Vec<u8>>::from_raw(
-            viewport.width,
-            viewport.height,
-            pixels.iter().flat_map(|v| *v).collect::<Vec<_>>(),
-        )
-        .unwrap(),
-    );
-
-    info!("Time render: {:?}", std::time::Instant::now() - start);
-
-    Ok(img)
+    /// The model and its ground, if it has a contact shadow.
+    fn objects(&self) -> impl Iterator<Item = &dyn Object> {
+        self.mesh
+            .into_iter()
+            .chain(self.ground.iter().flat_map(|ground| ground.into_iter()))
+    }
 }
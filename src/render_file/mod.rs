@@ -1,19 +1,33 @@
 use std::path::Path;
 
-use image::DynamicImage;
 use three_d::*;
 
+use crate::limits::Limits;
+use crate::output::{self, Format};
+use crate::render;
+use crate::storage;
+
 pub async fn run_multiple(
     input: &String,
     results: &String,
     context: &HeadlessContext,
     texture_url: &Option<&String>,
+    output_options: &output::Options,
+    render_options: &render::Options,
 ) {
     let files = std::fs::read_dir(input).unwrap();
     for file in files {
         let entry = file.unwrap();
         let path = entry.path();
-        run(path.to_str().unwrap(), results, context, texture_url).await;
+        run(
+            path.to_str().unwrap(),
+            results,
+            context,
+            texture_url,
+            output_options,
+            render_options,
+        )
+        .await;
     }
 }
 
@@ -22,12 +36,13 @@ pub async fn run(
     results_path: &String,
     context: &HeadlessContext,
     texture_url: &Option<&String>,
+    output_options: &output::Options,
+    render_options: &render::Options,
 ) {
     let start = std::time::Instant::now();
 
     println!("Running: {}", model_path);
 
-    let factor = 2;
     let width = 2222;
     let height = 2000;
 
@@ -40,14 +55,17 @@ pub async fn run(
 
     let textures = vec![texture];
 
-    let maybe_pixels = crate::render::render_urls(
+    let maybe_pixels = render::render_urls(
         Some(String::from(model_path)),
         None,
         textures,
         context,
-        width * factor,
-        height * factor,
+        width,
+        height,
+        render_options,
+        &Limits::default(),
         &String::new(),
+        &storage::Settings::default(),
     )
     .await;
 
@@ -56,19 +74,17 @@ pub async fn run(
         return;
     }
 
-    let pixels = maybe_pixels.unwrap();
+    let img = maybe_pixels.unwrap();
 
-    let img: DynamicImage = image::imageops::thumbnail(&pixels, width, height).into();
+    let body = output::encode(&img, Format::Webp, output_options).unwrap();
 
-    let mut writer = std::fs::File::create(
+    std::fs::write(
         Path::new(results_path)
             .join(Path::new(&model_path).file_name().unwrap())
-            .with_extension("webp"),
+            .with_extension(Format::Webp.extension()),
+        body,
     )
     .unwrap();
 
-    img.write_to(&mut writer, image::ImageOutputFormat::WebP)
-        .unwrap();
-
     println!("Time: {:?}", std::time::Instant::now() - start);
 }
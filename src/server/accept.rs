@@ -0,0 +1,104 @@
+use crate::output::Format;
+
+/// Server preference among formats the client lists explicitly with the same quality.
+const PREFERENCE: [Format; 4] = [Format::Webp, Format::Png, Format::Jpeg, Format::Avif];
+
+/// The format used when the client accepts anything (`*/*`, `image/*` or no `Accept` at all).
+const DEFAULT: Format = Format::Png;
+
+struct MediaRange {
+    mime: String,
+    q: f32,
+}
+
+/// Picks the output format from an `Accept` header honoring q-values.
+/// Wildcards only select the default format, other formats have to be listed explicitly,
+/// and when nothing supported is acceptable the default format is used anyway.
+pub fn negotiate(accept_header: Option<&str>) -> Format {
+    let Some(header) = accept_header else {
+        return DEFAULT;
+    };
+
+    let ranges = parse(header);
+
+    let mut best = DEFAULT;
+    let mut best_q = 0.0;
+
+    for format in PREFERENCE {
+        let explicit = ranges
+            .iter()
+            .find(|range| Format::from_content_type(&range.mime) == Some(format))
+            .map(|range| range.q);
+
+        let q = match explicit {
+            Some(q) => q,
+            None if format == DEFAULT => wildcard_quality(&ranges),
+            None => 0.0,
+        };
+
+        if q > best_q {
+            best = format;
+            best_q = q;
+        }
+    }
+
+    best
+}
+
+fn wildcard_quality(ranges: &[MediaRange]) -> f32 {
+    ranges
+        .iter()
+        .find(|range| range.mime == "image/*")
+        .or_else(|| ranges.iter().find(|range| range.mime == "*/*"))
+        .map(|range| range.q)
+        .unwrap_or(0.0)
+}
+
+fn parse(header: &str) -> Vec<MediaRange> {
+    header
+        .split(',')
+        .filter_map(|part| {
+            let mut params = part.split(';');
+            let mime = params.next()?.trim().to_lowercase();
+            if mime.is_empty() {
+                return None;
+            }
+
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0)
+                .clamp(0.0, 1.0);
+
+            Some(MediaRange { mime, q })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let cases = [
+            (None, Format::Png),
+            (Some("*/*"), Format::Png),
+            (Some("image/webp"), Format::Webp),
+            (Some("image/jpeg"), Format::Jpeg),
+            (Some("image/avif;q=0.9, image/jpeg;q=0.5"), Format::Avif),
+            (Some("image/webp,image/png"), Format::Webp),
+            (Some("image/jpeg;q=0.5, image/*"), Format::Png),
+            (Some("image/webp;q=0, */*;q=0.1"), Format::Png),
+            (Some("image/gif"), Format::Png),
+            (
+                Some("image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8"),
+                Format::Webp,
+            ),
+        ];
+
+        for (header, expected) in cases {
+            assert_eq!(negotiate(header), expected, "{:?}", header);
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures_util::stream;
+use image::DynamicImage;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+use crate::background::Layer;
+use crate::output::{self, Format};
+use crate::render::{download_textures, load_model, PreparedModel};
+
+use super::request::BatchRequest;
+use super::server::{encode, failed, rejected};
+use super::shutdown::Shutdown;
+use super::{accept, config, health, reload};
+
+/// Where the render loop sends the images of a batch, it holds the render slot of the batch,
+/// so the slot is released as soon as the loop is done with the last item.
+pub(crate) struct BatchChannel {
+    images_tx: mpsc::Sender<Result<DynamicImage>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl BatchChannel {
+    /// Waits for the encoder to take the image, fails when the client went away.
+    async fn send(&self, pixels: Result<DynamicImage>) -> Result<()> {
+        self.images_tx
+            .send(pixels)
+            .await
+            .map_err(|_| anyhow::anyhow!("batch client went away"))
+    }
+}
+
+/// Texture sets downloaded ahead of the one being rendered.
+const PREFETCH: usize = 2;
+
+/// Renders every texture set of the batch with a single model load and mesh upload,
+/// sending the images one by one as they are done. The textures of the next sets are
+/// downloaded while the current one renders, so the render loop waits for the GPU only.
+pub(crate) async fn run(
+    request: BatchRequest,
+    images_tx: BatchChannel,
+    context: &three_d::Context,
+    config: &config::Config,
+    health_state: &health::State,
+) {
+    let start = std::time::Instant::now();
+
+    let render_options = request.render_options(config);
+    let local_model_dir = &config.models.local_model_dir;
+
+    let limits = config.limits;
+    let mut downloads = request
+        .texture_sets
+        .into_iter()
+        .map(|texture_urls| tokio::spawn(download_textures(texture_urls, limits)));
+    let mut pending: VecDeque<_> = downloads.by_ref().take(PREFETCH).collect();
+
+    // the background downloads along with the first textures while the model loads
+    let (background, shadow) = (request.background, request.shadow);
+    let (width, height) = (request.width, request.height);
+    let layer = tokio::spawn(async move {
+        Layer::load(&background, shadow, width, height, &limits)
+            .await
+            .context("loading background")
+    });
+
+    let model = load_model(
+        request.model_url,
+        request.model,
+        local_model_dir,
+        &config.storage,
+        render_options.scene.as_ref(),
+    )
+    .await;
+    let layer = layer
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|layer| layer);
+
+    let prepared = match (layer, model) {
+        (Ok(layer), Ok((model, doc, manifest))) => PreparedModel::new(
+            context,
+            &model,
+            &doc,
+            &manifest,
+            request.width,
+            request.height,
+            &render_options,
+        )
+        .map(|prepared| (layer, prepared)),
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    };
+
+    let (layer, mut prepared) = match prepared {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            pending.iter().for_each(|download| download.abort());
+            let _ = images_tx.send(Err(e)).await;
+            return;
+        }
+    };
+
+    log::info!("Batch model load: {:?}", start.elapsed());
+
+    while let Some(download) = pending.pop_front() {
+        pending.extend(downloads.next());
+
+        let pixels = match download.await {
+            Ok(Ok(cpu_textures)) => prepared
+                .render(context, &cpu_textures)
+                .map(|pixels| layer.composite(pixels)),
+            Ok(Err(e)) => Err(e),
+            Err(e) => Err(e.into()),
+        };
+        health_state.beat();
+
+        // the client went away, no point in rendering the rest
+        if images_tx.send(pixels).await.is_err() {
+            pending.iter().for_each(|download| download.abort());
+            break;
+        }
+    }
+
+    log::info!("Batch time overall: {:?}", start.elapsed());
+}
+
+/// POST `/render-batch`, responds with a `multipart/mixed` stream with one part per texture set,
+/// in the order of `texture_sets`. Failed items are sent as `application/json` parts with an error.
+pub fn post(
+    sem: Arc<Semaphore>,
+    batch_tx: mpsc::Sender<(BatchRequest, BatchChannel)>,
+    shutdown: Arc<Shutdown>,
+    shared: Arc<reload::Shared>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::post()
+        .and(warp::path("render-batch"))
+        .and(warp::body::json())
+        .and(warp::header::optional("accept"))
+        .and_then(move |mut r: BatchRequest, accept_header: Option<String>| {
+            let sem = sem.clone();
+            let batch_tx = batch_tx.clone();
+            let shutdown = shutdown.clone();
+            let config = shared.get();
+            async move {
+                if shutdown.is_draining() {
+                    return Ok::<Response, warp::Rejection>(
+                        warp::reply::with_status("shutting down", StatusCode::SERVICE_UNAVAILABLE)
+                            .into_response(),
+                    );
+                }
+
+                let format = r
+                    .format
+                    .unwrap_or_else(|| accept::negotiate(accept_header.as_deref()));
+                let options = config
+                    .output
+                    .options(format, r.quality, r.alpha_quality, r.lossless);
+
+                if let Err(e) = r.resolve_product(&config) {
+                    return failed(e);
+                }
+
+                let checked = config
+                    .check_limits(r.width, r.height, r.supersample)
+                    .and_then(|_| config.limits.check_texture_sets(r.texture_sets.len()));
+                if let Err(e) = checked {
+                    return Ok(rejected(&e));
+                }
+
+                let permit = sem.acquire_owned().await.unwrap();
+                let (images_tx, images_rx) = mpsc::channel(1);
+                let channel = BatchChannel {
+                    images_tx,
+                    _permit: permit,
+                };
+                if let Err(e) = batch_tx.try_send((r, channel)) {
+                    log::error!("Error: could not queue batch: {}", e);
+                    return Ok(warp::reply::with_status(
+                        "could not queue batch",
+                        StatusCode::SERVICE_UNAVAILABLE,
+                    )
+                    .into_response());
+                }
+
+                let boundary = uuid::Uuid::new_v4().simple().to_string();
+                // parts are encoded as the images come in and buffered for the client,
+                // a slow client must not hold up the render loop
+                let (parts_tx, parts_rx) = mpsc::unbounded_channel();
+                tokio::spawn(encode_parts(
+                    images_rx,
+                    parts_tx,
+                    boundary.clone(),
+                    format,
+                    options,
+                ));
+                let body = stream::unfold(parts_rx, |mut parts_rx| async move {
+                    let part = parts_rx.recv().await?;
+                    Some((Ok::<_, Infallible>(part), parts_rx))
+                });
+
+                Ok(warp::http::response::Builder::new()
+                    .header(
+                        "Content-Type",
+                        format!("multipart/mixed; boundary={}", boundary),
+                    )
+                    .header("Vary", "Accept")
+                    .body(warp::hyper::Body::wrap_stream(body))
+                    .unwrap())
+            }
+        })
+}
+
+/// Encodes rendered images into multipart parts off the render loop, until the batch is done
+/// or the client went away. Dropping `images_rx` then stops the rest of the batch.
+async fn encode_parts(
+    mut images_rx: mpsc::Receiver<Result<DynamicImage>>,
+    parts_tx: mpsc::UnboundedSender<Bytes>,
+    boundary: String,
+    format: Format,
+    options: output::Options,
+) {
+    let mut index = 0;
+    while let Some(pixels) = images_rx.recv().await {
+        let body = match pixels {
+            Ok(pixels) => tokio::task::spawn_blocking(move || encode(format, options, pixels))
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|body| body),
+            Err(e) => Err(e),
+        };
+
+        let part = match body {
+            Ok(body) => part(&boundary, index, format.content_type(), &body),
+            Err(e) => {
+                log::error!("Batch item {} failed: {}", index, e);
+                let error = serde_json::json!({
+                    "index": index,
+                    "error": e.to_string(),
+                });
+                part(
+                    &boundary,
+                    index,
+                    "application/json",
+                    error.to_string().as_bytes(),
+                )
+            }
+        };
+        if parts_tx.send(part).is_err() {
+            return;
+        }
+        index += 1;
+    }
+
+    let _ = parts_tx.send(Bytes::from(format!("--{}--\r\n", boundary)));
+}
+
+fn part(boundary: &str, index: usize, content_type: &str, body: &[u8]) -> Bytes {
+    let mut part = format!(
+        "--{}\r\nContent-Type: {}\r\nContent-Disposition: inline; name=\"{}\"\r\nContent-Length: {}\r\n\r\n",
+        boundary,
+        content_type,
+        index,
+        body.len()
+    )
+    .into_bytes();
+    part.extend_from_slice(body);
+    part.extend_from_slice(b"\r\n");
+    Bytes::from(part)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_encode_parts() {
+        let (images_tx, images_rx) = mpsc::channel(1);
+        let (parts_tx, mut parts_rx) = mpsc::unbounded_channel();
+        let encoder = tokio::spawn(encode_parts(
+            images_rx,
+            parts_tx,
+            "b".to_string(),
+            Format::Png,
+            Default::default(),
+        ));
+
+        images_tx
+            .send(Ok(DynamicImage::new_rgba8(4, 4)))
+            .await
+            .unwrap();
+        images_tx
+            .send(Err(anyhow::anyhow!("no textures")))
+            .await
+            .unwrap();
+        drop(images_tx);
+        // every part is encoded without anyone reading them
+        encoder.await.unwrap();
+
+        let mut parts = vec![];
+        while let Some(part) = parts_rx.recv().await {
+            parts.push(String::from_utf8_lossy(&part).to_string());
+        }
+
+        assert_eq!(parts.len(), 3);
+        assert!(parts[0].starts_with("--b\r\nContent-Type: image/png\r\n"));
+        assert!(parts[1].contains("name=\"1\""));
+        assert!(parts[1].contains("no textures"));
+        assert_eq!(parts[2], "--b--\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_batch_channel_holds_the_slot() {
+        let sem = Arc::new(Semaphore::new(1));
+        let (images_tx, mut images_rx) = mpsc::channel(1);
+        let channel = BatchChannel {
+            images_tx,
+            _permit: sem.clone().acquire_owned().await.unwrap(),
+        };
+
+        channel
+            .send(Ok(DynamicImage::new_rgba8(1, 1)))
+            .await
+            .unwrap();
+        assert_eq!(sem.available_permits(), 0);
+
+        // released when the render loop is done, before anything was read
+        drop(channel);
+        assert_eq!(sem.available_permits(), 1);
+        assert!(images_rx.recv().await.is_some());
+
+        // a gone client fails the send
+        let (images_tx, images_rx) = mpsc::channel(1);
+        drop(images_rx);
+        let channel = BatchChannel {
+            images_tx,
+            _permit: sem.clone().acquire_owned().await.unwrap(),
+        };
+        assert!(channel
+            .send(Ok(DynamicImage::new_rgba8(1, 1)))
+            .await
+            .is_err());
+    }
+}
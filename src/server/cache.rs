@@ -0,0 +1,531 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+
+use crate::antialias;
+use crate::background::{Background, DropShadow};
+use crate::color::Color;
+use crate::composite::BlendMode;
+use crate::gltf::{SceneSelector, Visibility};
+use crate::manifest;
+use crate::mockup;
+use crate::output::{self, Format};
+use crate::shadow::ContactShadow;
+
+use super::config;
+use super::metrics::Metrics;
+use super::request::Request;
+
+/// Bumped whenever a change of the renderer changes its results.
+const VERSION: u32 = 1;
+
+/// Everything a render depends on, byte fields are replaced by their hashes.
+#[derive(Serialize)]
+struct Normalized<'a> {
+    version: u32,
+    model: String,
+    texture_urls: &'a Option<Vec<String>>,
+    textures: Option<Vec<String>>,
+    width: u32,
+    height: u32,
+    background: &'a Background,
+    shadow: &'a Option<DropShadow>,
+    contact_shadow: &'a Option<ContactShadow>,
+    scene: &'a Option<SceneSelector>,
+    visibility: &'a Visibility,
+    colors: &'a BTreeMap<String, Color>,
+    mask_url: &'a Option<String>,
+    mask: Option<String>,
+    blend: Option<BlendMode>,
+    mockup: Option<mockup::Maps>,
+    /// Hashes of local map files, urls of remote ones.
+    mockup_files: Vec<String>,
+    /// `max_width`, `max_height` and `max_render_pixels`, the size of a masked render is fit into them.
+    mask_limits: Option<(u32, u32, u64)>,
+    format: Format,
+    output: output::Options,
+    antialias: antialias::Settings,
+}
+
+/// Encoded results of renders in memory and, optionally, on disk.
+/// The settings are passed to every call, so a reloaded config applies right away.
+pub struct Cache {
+    memory: Mutex<Memory>,
+    disk: Mutex<Option<Disk>>,
+    /// Hashes of local files by path, with the modification time and size they were taken at.
+    file_hashes: Mutex<HashMap<PathBuf, (SystemTime, u64, String)>>,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Default)]
+struct Memory {
+    entries: HashMap<String, Arc<Vec<u8>>>,
+    /// Least recently used first.
+    order: VecDeque<String>,
+    bytes: u64,
+}
+
+/// The files of the disk tier, listed once and then tracked as they are written and evicted.
+struct Disk {
+    dir: PathBuf,
+    /// Keys and sizes, oldest first.
+    files: VecDeque<(String, u64)>,
+    bytes: u64,
+}
+
+impl Cache {
+    pub fn new_arc(metrics: Arc<Metrics>) -> Arc<Self> {
+        Arc::new(Cache {
+            memory: Mutex::new(Memory::default()),
+            disk: Mutex::new(None),
+            file_hashes: Mutex::new(HashMap::new()),
+            metrics,
+        })
+    }
+
+    /// Key of a request, with its product already resolved. `None` when caching is disabled
+    /// or the model is not available locally yet, it is cached once it has been downloaded.
+    pub fn key(
+        &self,
+        request: &Request,
+        format: Format,
+        options: output::Options,
+        config: &config::Config,
+    ) -> Option<String> {
+        if !config.cache.enabled {
+            return None;
+        }
+
+        let model = self.model_hash(request, &config.models)?;
+        let model_url = request.model_url.as_deref().unwrap_or_default();
+
+        // the maps only apply to renders blended onto a mask
+        let mockup = match request.has_mask() {
+            true => config.models.mockup_maps(model_url).ok()?,
+            false => None,
+        };
+        let mockup_files = match &mockup {
+            Some((maps, dir)) => maps
+                .locations(dir)
+                .into_iter()
+                .map(|location| match location.starts_with("http") {
+                    true => Some(location),
+                    false => self.file_hash(Path::new(&location)),
+                })
+                .collect::<Option<Vec<String>>>()?,
+            None => vec![],
+        };
+
+        let normalized = Normalized {
+            version: VERSION,
+            model,
+            texture_urls: &request.texture_urls,
+            textures: request
+                .textures
+                .as_ref()
+                .map(|textures| textures.iter().map(|bytes| hash(bytes)).collect()),
+            width: request.width,
+            height: request.height,
+            background: &request.background,
+            shadow: &request.shadow,
+            contact_shadow: &request.contact_shadow,
+            scene: &request.scene,
+            visibility: &request.visibility,
+            colors: &request.colors,
+            mask_url: &request.mask_url,
+            mask: request.mask.as_deref().map(hash),
+            blend: request.blend,
+            mockup: mockup.map(|(maps, _)| maps),
+            mockup_files,
+            mask_limits: request.has_mask().then_some((
+                config.limits.max_width,
+                config.limits.max_height,
+                config.limits.max_render_pixels,
+            )),
+            format,
+            output: options,
+            antialias: request.render_options(config).antialias,
+        };
+
+        let json = serde_json::to_vec(&normalized).ok()?;
+        Some(hash(&json))
+    }
+
+    /// Hash of the model and its manifest, which changes the camera, textures and mask.
+    fn model_hash(&self, request: &Request, models: &config::Models) -> Option<String> {
+        let local_path = request
+            .model_url
+            .as_deref()
+            .and_then(|url| Path::new(url).file_name())
+            .map(|file_name| Path::new(&models.local_model_dir).join(file_name));
+
+        let mut model = match (&request.model, &local_path) {
+            (Some(bytes), _) => hash(bytes),
+            (None, Some(path)) => self.file_hash(path)?,
+            (None, None) => return None,
+        };
+
+        if let Some(sidecar) = local_path.as_deref().and_then(manifest::sidecar) {
+            model.push_str(&self.file_hash(&sidecar)?);
+        }
+
+        Some(model)
+    }
+
+    fn file_hash(&self, path: &Path) -> Option<String> {
+        let metadata = std::fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?;
+
+        let mut file_hashes = self.file_hashes.lock().unwrap();
+        if let Some((at, size, file_hash)) = file_hashes.get(path) {
+            if *at == modified && *size == metadata.len() {
+                return Some(file_hash.clone());
+            }
+        }
+
+        let file_hash = hash(&std::fs::read(path).ok()?);
+        file_hashes.insert(
+            path.to_path_buf(),
+            (modified, metadata.len(), file_hash.clone()),
+        );
+        Some(file_hash)
+    }
+
+    /// Looks the result up in memory, then on disk, a result found on disk is kept in memory.
+    pub fn get(&self, key: &str, settings: &config::Cache) -> Option<Arc<Vec<u8>>> {
+        if let Some(body) = self.memory.lock().unwrap().get(key) {
+            self.metrics.cache_lookup("memory");
+            return Some(body);
+        }
+
+        let body = settings
+            .disk_dir
+            .as_ref()
+            .and_then(|dir| std::fs::read(Path::new(dir).join(key)).ok());
+        let Some(body) = body else {
+            self.metrics.cache_lookup("miss");
+            return None;
+        };
+
+        self.metrics.cache_lookup("disk");
+        let body = Arc::new(body);
+        self.memory
+            .lock()
+            .unwrap()
+            .insert(key, body.clone(), settings.memory_max_bytes);
+        Some(body)
+    }
+
+    /// Counts a request answered with `304 Not Modified`.
+    pub fn not_modified(&self) {
+        self.metrics.cache_lookup("not_modified");
+    }
+
+    pub fn insert(&self, key: &str, body: Arc<Vec<u8>>, settings: &config::Cache) {
+        self.memory
+            .lock()
+            .unwrap()
+            .insert(key, body.clone(), settings.memory_max_bytes);
+
+        if let Some(dir) = &settings.disk_dir {
+            let mut disk = self.disk.lock().unwrap();
+            if let Err(e) = write_disk(
+                &mut disk,
+                Path::new(dir),
+                key,
+                &body,
+                settings.disk_max_bytes,
+            ) {
+                log::warn!("Could not write {} to the cache in {}: {}", key, dir, e);
+            }
+        }
+    }
+}
+
+impl Memory {
+    fn get(&mut self, key: &str) -> Option<Arc<Vec<u8>>> {
+        let body = self.entries.get(key)?.clone();
+        if let Some(position) = self.order.iter().position(|k| k == key) {
+            self.order.remove(position);
+        }
+        self.order.push_back(key.to_string());
+        Some(body)
+    }
+
+    fn insert(&mut self, key: &str, body: Arc<Vec<u8>>, max_bytes: u64) {
+        if let Some(previous) = self.entries.insert(key.to_string(), body.clone()) {
+            self.bytes -= previous.len() as u64;
+            if let Some(position) = self.order.iter().position(|k| k == key) {
+                self.order.remove(position);
+            }
+        }
+        self.order.push_back(key.to_string());
+        self.bytes += body.len() as u64;
+
+        while self.bytes > max_bytes {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = self.entries.remove(&oldest) {
+                self.bytes -= evicted.len() as u64;
+            }
+        }
+    }
+}
+
+impl Disk {
+    /// Lists the results already in `dir`, e.g. from before a restart.
+    fn list(dir: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let mut files: Vec<(SystemTime, u64, String)> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                (metadata.is_file() && entry.path().extension().is_none()).then_some((
+                    metadata.modified().ok()?,
+                    metadata.len(),
+                    entry.file_name().to_str()?.to_string(),
+                ))
+            })
+            .collect();
+        files.sort();
+
+        Ok(Disk {
+            dir: dir.to_path_buf(),
+            bytes: files.iter().map(|(_, size, _)| size).sum(),
+            files: files
+                .into_iter()
+                .map(|(_, size, key)| (key, size))
+                .collect(),
+        })
+    }
+}
+
+/// Writes the result through a temporary file and evicts the oldest ones over `max_bytes`.
+/// The directory is only listed on the first write (and when `dir` changes).
+fn write_disk(
+    disk: &mut Option<Disk>,
+    dir: &Path,
+    key: &str,
+    body: &[u8],
+    max_bytes: u64,
+) -> std::io::Result<()> {
+    let disk = match disk {
+        Some(disk) if disk.dir == dir => disk,
+        _ => disk.insert(Disk::list(dir)?),
+    };
+
+    let partial = dir.join(format!("{}.part", key));
+    std::fs::write(&partial, body)?;
+    std::fs::rename(&partial, dir.join(key))?;
+
+    if let Some(position) = disk.files.iter().position(|(k, _)| k == key) {
+        if let Some((_, size)) = disk.files.remove(position) {
+            disk.bytes -= size;
+        }
+    }
+    disk.files.push_back((key.to_string(), body.len() as u64));
+    disk.bytes += body.len() as u64;
+
+    while disk.bytes > max_bytes {
+        let Some((oldest, size)) = disk.files.pop_front() else {
+            break;
+        };
+        disk.bytes -= size;
+        match std::fs::remove_file(dir.join(&oldest)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether an `If-None-Match` header lists the ETag of the key.
+pub fn matches(if_none_match: Option<&str>, key: &str) -> bool {
+    let Some(if_none_match) = if_none_match else {
+        return false;
+    };
+
+    if_none_match.split(',').any(|tag| {
+        let tag = tag.trim();
+        tag == "*" || tag.trim_start_matches("W/") == etag(key)
+    })
+}
+
+pub fn etag(key: &str) -> String {
+    format!("\"{}\"", key)
+}
+
+fn hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha1::digest(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(dir: Option<&Path>) -> config::Cache {
+        config::Cache {
+            enabled: true,
+            memory_max_bytes: 8,
+            disk_dir: dir.map(|dir| dir.to_string_lossy().to_string()),
+            disk_max_bytes: 8,
+        }
+    }
+
+    #[test]
+    fn test_key() {
+        let cache = Cache::new_arc(Metrics::new_arc());
+        let mut config = config::Config::default();
+        config.cache.enabled = true;
+        config.models.local_model_dir = "testdata".to_string();
+
+        let request = |width| Request {
+            model_url: Some("https://example.com/gltf/canary.glb".to_string()),
+            texture_urls: Some(vec!["https://example.com/canvas.png".to_string()]),
+            width,
+            height: 100,
+            ..Default::default()
+        };
+        let options = output::Options::default();
+        let key = |request: &Request, config: &config::Config| {
+            cache.key(request, Format::Webp, options, config)
+        };
+
+        assert!(key(&request(100), &config).is_some());
+        assert_eq!(key(&request(100), &config), key(&request(100), &config));
+        assert_ne!(key(&request(100), &config), key(&request(200), &config));
+        assert_ne!(
+            key(&request(100), &config),
+            cache.key(&request(100), Format::Png, options, &config)
+        );
+
+        let mut colored = request(100);
+        colored
+            .colors
+            .insert("Handle".to_string(), Color([255, 0, 0, 255]));
+        assert_ne!(key(&request(100), &config), key(&colored, &config));
+
+        // a model that is not local yet cannot be keyed by its content
+        let mut missing = request(100);
+        missing.model_url = Some("https://example.com/gltf/missing.glb".to_string());
+        assert!(key(&missing, &config).is_none());
+
+        config.cache.enabled = false;
+        assert!(key(&request(100), &config).is_none());
+    }
+
+    #[test]
+    fn test_key_mockup_files() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("gimme-3d-maps-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let map = dir.join("canary.shading.png");
+        std::fs::write(&map, [1])?;
+
+        let cache = Cache::new_arc(Metrics::new_arc());
+        let mut config = config::Config::default();
+        config.cache.enabled = true;
+        config.models.local_model_dir = "testdata".to_string();
+        config.models.mockups.insert(
+            "canary.glb".to_string(),
+            mockup::Maps {
+                shading_map: Some(map.to_string_lossy().to_string()),
+                ..Default::default()
+            },
+        );
+
+        let request = Request {
+            model_url: Some("https://example.com/gltf/canary.glb".to_string()),
+            mask_url: Some("https://example.com/mask.png".to_string()),
+            ..Default::default()
+        };
+        let key = || cache.key(&request, Format::Webp, Default::default(), &config);
+
+        let before = key();
+        assert!(before.is_some());
+        std::fs::write(&map, [1, 2])?;
+        assert_ne!(key(), before);
+
+        std::fs::remove_file(&map)?;
+        assert!(key().is_none());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_mask_limits() {
+        let cache = Cache::new_arc(Metrics::new_arc());
+        let mut config = config::Config::default();
+        config.cache.enabled = true;
+        config.models.local_model_dir = "testdata".to_string();
+
+        let plain = Request {
+            model_url: Some("https://example.com/gltf/canary.glb".to_string()),
+            ..Default::default()
+        };
+        let masked = Request {
+            model_url: plain.model_url.clone(),
+            mask_url: Some("https://example.com/mask.png".to_string()),
+            ..Default::default()
+        };
+        let keys = |config: &config::Config| {
+            (
+                cache.key(&plain, Format::Png, Default::default(), config),
+                cache.key(&masked, Format::Png, Default::default(), config),
+            )
+        };
+
+        let (plain_before, masked_before) = keys(&config);
+        config.limits.max_width /= 2;
+        // the size of a masked render follows the limits, an explicit size does not
+        let (plain_after, masked_after) = keys(&config);
+        assert_eq!(plain_after, plain_before);
+        assert_ne!(masked_after, masked_before);
+
+        config.limits.max_render_pixels /= 2;
+        assert_ne!(keys(&config).1, masked_after);
+    }
+
+    #[test]
+    fn test_tiers() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("gimme-3d-cache-{}", std::process::id()));
+        let cache = Cache::new_arc(Metrics::new_arc());
+        let settings = settings(Some(&dir));
+
+        cache.insert("a", Arc::new(vec![1; 4]), &settings);
+        cache.insert("b", Arc::new(vec![2; 4]), &settings);
+        assert_eq!(cache.get("a", &settings).unwrap().as_slice(), &[1; 4]);
+
+        // "b" is the least recently used one and is evicted from memory,
+        // the oldest file "a" is evicted from disk
+        cache.insert("c", Arc::new(vec![3; 4]), &settings);
+        assert!(!cache.memory.lock().unwrap().entries.contains_key("b"));
+        assert!(!dir.join("a").exists());
+        assert_eq!(cache.get("b", &settings).unwrap().as_slice(), &[2; 4]);
+        assert!(cache.get("d", &settings).is_none());
+
+        // after a restart the files already on disk count towards the limit
+        let cache = Cache::new_arc(Metrics::new_arc());
+        cache.insert("d", Arc::new(vec![4; 4]), &settings);
+        assert!(!dir.join("b").exists());
+        assert!(dir.join("c").exists() && dir.join("d").exists());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches(Some("\"abc\""), "abc"));
+        assert!(matches(Some("W/\"xyz\", \"abc\""), "abc"));
+        assert!(matches(Some("*"), "abc"));
+        assert!(!matches(Some("\"xyz\""), "abc"));
+        assert!(!matches(None, "abc"));
+    }
+}
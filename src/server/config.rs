@@ -1,21 +1,379 @@
-use anyhow::Result;
-use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
 
-#[derive(Deserialize)]
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::limits::{LimitError, Limits};
+use crate::manifest::{self, Manifest};
+use crate::output::{self, Format};
+use crate::{antialias, mockup, storage};
+
+/// Prefix of the environment variables overriding config fields.
+pub const ENV_PREFIX: &str = "GIMME3D_";
+
+/// Every field is optional, missing ones get their defaults.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct Config {
     pub port: u16,
+    /// Default supersample factor, the scene is rendered this many times larger and downsampled.
     pub upscale_factor: u32,
+    /// Seconds to wait for in-flight renders after SIGTERM before exiting.
+    pub drain_timeout_secs: u64,
+    /// Seconds between checks of the config file for changes, 0 only reloads on SIGHUP.
+    pub reload_interval_secs: u64,
     pub models: Models,
+    pub health: Health,
+    pub prefetch: Prefetch,
+    pub cache: Cache,
+    pub jobs: Jobs,
+    pub output: Output,
+    pub antialias: Antialias,
+    pub limits: Limits,
+    pub storage: storage::Settings,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct Models {
     pub local_model_dir: String,
     pub models_base_url: String,
     pub models: Vec<String>,
+    /// Shading and displacement maps of the product photos, by model file name.
+    pub mockups: HashMap<String, mockup::Maps>,
+}
+
+impl Default for Models {
+    fn default() -> Self {
+        Self {
+            local_model_dir: "models".to_string(),
+            models_base_url: "".to_string(),
+            models: vec![],
+            mockups: HashMap::new(),
+        }
+    }
+}
+
+impl Models {
+    /// File name of the model of a product and its manifest (empty if it has none).
+    pub fn product(&self, id: &str) -> Result<(String, Manifest)> {
+        let file_name = manifest::product_model(&self.local_model_dir, id)?;
+        let manifest = self.manifest(&file_name)?.unwrap_or_default();
+        Ok((file_name, manifest))
+    }
+
+    /// Manifest of a model in the local model directory, looked up by the file name of its url.
+    pub fn manifest(&self, model_url: &str) -> Result<Option<Manifest>> {
+        match Path::new(model_url).file_name() {
+            Some(name) => Manifest::load(&Path::new(&self.local_model_dir).join(name)),
+            None => Ok(None),
+        }
+    }
+
+    /// Mockup maps of a model, looked up by the file name of its url.
+    pub fn mockup(&self, model_url: &str) -> Option<&mockup::Maps> {
+        let name = Path::new(model_url).file_name()?.to_str()?;
+        self.mockups.get(name)
+    }
+
+    /// Mockup maps a model is rendered with and the directory their relative paths are
+    /// resolved against, the maps of its manifest win over the ones of the config.
+    pub fn mockup_maps(&self, model_url: &str) -> Result<Option<(mockup::Maps, String)>> {
+        if let Some(Manifest {
+            mockup: Some(maps),
+            dir,
+            ..
+        }) = self.manifest(model_url)?
+        {
+            return Ok(Some((maps, dir.to_string_lossy().to_string())));
+        }
+
+        Ok(self
+            .mockup(model_url)
+            .map(|maps| (maps.clone(), self.local_model_dir.clone())))
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Health {
+    /// The render loop is considered dead after this many seconds without a heartbeat,
+    /// keep it above the slowest expected render.
+    pub heartbeat_timeout_secs: u64,
+    /// Periodically render a tiny built-in scene and report the outcome in `/readyz`.
+    pub canary: bool,
+    pub canary_interval_secs: u64,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            heartbeat_timeout_secs: 60,
+            canary: false,
+            canary_interval_secs: 60,
+        }
+    }
+}
+
+/// Downloading of the configured models at startup, before the first request needs them.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Prefetch {
+    pub enabled: bool,
+    /// How many models are downloaded at the same time.
+    pub concurrency: usize,
+    /// Share of the models (0-1) that must be available before `/readyz` succeeds,
+    /// it also succeeds once every model has been tried.
+    pub ready_ratio: f64,
+}
+
+impl Default for Prefetch {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            concurrency: 4,
+            ready_ratio: 1.,
+        }
+    }
+}
+
+/// Cache of encoded renders of `/render` and `/render-form`, keyed by the content of the request.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Cache {
+    pub enabled: bool,
+    /// Size of the in-memory tier, the least recently used results are evicted first.
+    pub memory_max_bytes: u64,
+    /// Directory of the disk tier, only the memory tier is used when not set.
+    pub disk_dir: Option<String>,
+    /// Size of the disk tier, the oldest results are evicted first.
+    pub disk_max_bytes: u64,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            memory_max_bytes: 64 * 1024 * 1024,
+            disk_dir: None,
+            disk_max_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Jobs {
+    /// Upper bound of jobs kept in memory, finished jobs are evicted oldest first.
+    pub max_jobs: usize,
+    pub callback_timeout_secs: u64,
+    /// Hosts a `callback_url` may point at, callbacks are disabled without any.
+    pub callback_hosts: Vec<String>,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Self {
+            max_jobs: 32,
+            callback_timeout_secs: 10,
+            callback_hosts: vec![],
+        }
+    }
+}
+
+impl Jobs {
+    /// Rejects callback urls that are not `http(s)://` urls of one of the `callback_hosts`,
+    /// the server would post to any address it can reach on behalf of the client.
+    pub fn check_callback_url(&self, url: &str) -> Result<(), Error> {
+        let forbidden = || Error::ForbiddenCallback(url.to_string());
+        let parsed = reqwest::Url::parse(url).map_err(|_| forbidden())?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(forbidden());
+        }
+
+        let host = parsed.host_str().unwrap_or_default();
+        match self
+            .callback_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+        {
+            true => Ok(()),
+            false => Err(forbidden()),
+        }
+    }
+}
+
+/// Encoder defaults, used when a request does not set them.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Output {
+    pub webp_lossless: bool,
+    pub webp_quality: u8,
+    pub webp_alpha_quality: u8,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self {
+            webp_lossless: true,
+            webp_quality: output::DEFAULT_WEBP_QUALITY,
+            webp_alpha_quality: output::DEFAULT_WEBP_ALPHA_QUALITY,
+        }
+    }
+}
+
+impl Output {
+    /// Encoder options for a request, values set by the request win over the defaults.
+    /// A request asking for a webp quality gets a lossy webp unless it asks for lossless explicitly.
+    pub fn options(
+        &self,
+        format: Format,
+        quality: Option<u8>,
+        alpha_quality: Option<u8>,
+        lossless: Option<bool>,
+    ) -> output::Options {
+        if format != Format::Webp {
+            return output::Options {
+                quality,
+                alpha_quality,
+                lossless: false,
+            };
+        }
+
+        output::Options {
+            quality: quality.or(Some(self.webp_quality)),
+            alpha_quality: alpha_quality.or(Some(self.webp_alpha_quality)),
+            lossless: lossless.unwrap_or(quality.is_none() && self.webp_lossless),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Antialias {
+    /// Default downsampling filter.
+    pub filter: antialias::Filter,
+    /// MSAA samples used on the GPU, 0 disables it.
+    pub msaa: u8,
+    /// Upper bound of the supersample factor a request may ask for.
+    pub max_supersample: u32,
+}
+
+impl Default for Antialias {
+    fn default() -> Self {
+        Self {
+            filter: antialias::Filter::default(),
+            msaa: 0,
+            max_supersample: 4,
+        }
+    }
 }
 
 impl Config {
+    /// Antialiasing of a request, values set by the request win over the defaults,
+    /// the supersample factor is capped by `max_supersample`.
+    pub fn antialias(
+        &self,
+        supersample: Option<u32>,
+        filter: Option<antialias::Filter>,
+    ) -> antialias::Settings {
+        let max_supersample = self.antialias.max_supersample.max(1);
+
+        antialias::Settings {
+            filter: filter.unwrap_or(self.antialias.filter),
+            supersample: supersample
+                .unwrap_or(self.upscale_factor)
+                .clamp(1, max_supersample),
+            msaa: self.antialias.msaa,
+        }
+    }
+
+    /// Checks the requested size, rendered with the request's (capped) supersample factor.
+    pub fn check_limits(
+        &self,
+        width: u32,
+        height: u32,
+        supersample: Option<u32>,
+    ) -> Result<(), LimitError> {
+        let supersample = self.antialias(supersample, None).supersample;
+        self.limits.check_output(width, height, supersample)
+    }
+
+    /// Reads the config file (the defaults without one) and applies the `GIMME3D_*` overrides
+    /// of the environment. A malformed file or override is an error pointing at the problem,
+    /// so is a value out of its range.
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::load_with(path, std::env::vars())
+    }
+
+    /// `load` with the given variables instead of the environment.
+    pub fn load_with(
+        path: &Path,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        let config = if path.exists() {
+            Self::parse_toml(path.to_string_lossy().to_string())
+                .with_context(|| format!("invalid config {}", path.display()))?
+        } else {
+            log::warn!("{} not found, using the default config", path.display());
+            Self::default()
+        };
+
+        let config = config.with_overrides(vars)?;
+        antialias::check_msaa(config.antialias.msaa)
+            .with_context(|| format!("invalid config {}: antialias.msaa", path.display()))?;
+
+        Ok(config)
+    }
+
+    /// Applies overrides like `GIMME3D_PORT=8080`, `__` separates nested fields
+    /// (`GIMME3D_LIMITS__MAX_WIDTH=2048`). Values are read as toml (`["a.glb", "b.glb"]`
+    /// for a list), anything else is a string. Variables without the prefix are ignored.
+    pub fn with_overrides(self, vars: impl IntoIterator<Item = (String, String)>) -> Result<Self> {
+        let mut overrides: Vec<(String, String)> = vars
+            .into_iter()
+            .filter_map(|(name, value)| Some((name.strip_prefix(ENV_PREFIX)?.to_string(), value)))
+            .collect();
+        if overrides.is_empty() {
+            return Ok(self);
+        }
+        overrides.sort();
+
+        let mut config = self;
+        for (name, value) in overrides {
+            let path: Vec<String> = name.to_lowercase().split("__").map(String::from).collect();
+            config = config
+                .with_override(&path, &value)
+                .with_context(|| format!("invalid {}{}", ENV_PREFIX, name))?;
+        }
+
+        Ok(config)
+    }
+
+    fn with_override(&self, path: &[String], value: &str) -> Result<Self> {
+        let table = toml::Table::try_from(self)?;
+
+        // a value that parses as toml but has the wrong type may be meant as a string
+        let mut result = override_table(table.clone(), path, parse_value(value))?.try_into();
+        if result.is_err() {
+            let string = toml::Value::String(value.to_string());
+            if let Ok(config) = override_table(table, path, string)?.try_into() {
+                result = Ok(config);
+            }
+        }
+        let config: Self = result?;
+
+        // unknown fields are dropped by serde, they would not survive a round trip
+        let round_trip = toml::Table::try_from(&config)?;
+        if lookup(&round_trip, path).is_none() {
+            return Err(anyhow!("unknown config field {}", path.join(".")));
+        }
+
+        Ok(config)
+    }
+
     pub fn parse_toml(path: String) -> Result<Self> {
         let config_file = std::fs::read_to_string(path)?;
         Self::parse(config_file)
@@ -27,16 +385,62 @@ impl Config {
     }
 }
 
+/// Sets the value at `path`, creating the tables on the way.
+fn override_table(
+    mut table: toml::Table,
+    path: &[String],
+    value: toml::Value,
+) -> Result<toml::Value> {
+    let (last, parents) = path
+        .split_last()
+        .ok_or_else(|| anyhow!("empty config field"))?;
+
+    let mut current = &mut table;
+    for key in parents {
+        current = current
+            .entry(key.clone())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("{} is not a section", key))?;
+    }
+    current.insert(last.clone(), value);
+
+    Ok(toml::Value::Table(table))
+}
+
+fn lookup<'a>(table: &'a toml::Table, path: &[String]) -> Option<&'a toml::Value> {
+    let (first, rest) = path.split_first()?;
+    let value = table.get(first)?;
+    match rest.is_empty() {
+        true => Some(value),
+        false => lookup(value.as_table()?, rest),
+    }
+}
+
+fn parse_value(value: &str) -> toml::Value {
+    format!("value = {}", value)
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             port: 3030,
             upscale_factor: 2,
-            models: Models {
-                local_model_dir: "models".to_string(),
-                models_base_url: "".to_string(),
-                models: vec![],
-            },
+            drain_timeout_secs: 30,
+            reload_interval_secs: 0,
+            models: Models::default(),
+            health: Health::default(),
+            prefetch: Prefetch::default(),
+            cache: Cache::default(),
+            jobs: Jobs::default(),
+            output: Output::default(),
+            antialias: Antialias::default(),
+            limits: Limits::default(),
+            storage: storage::Settings::default(),
         }
     }
 }
@@ -53,12 +457,180 @@ mod tests {
 
         assert_eq!(config.port, 3030);
         assert_eq!(config.upscale_factor, 2);
+        assert_eq!(config.drain_timeout_secs, 30);
+        assert_eq!(config.reload_interval_secs, 10);
         assert_eq!(config.models.local_model_dir, "/var/models/");
         assert_eq!(config.models.models_base_url, "https://foobar.com/gltf/");
         assert_eq!(config.models.models.len(), 2);
         assert_eq!(config.models.models[0], "foo.glb");
         assert_eq!(config.models.models[1], "bar.glb");
+        let mockup = config
+            .models
+            .mockup("https://foobar.com/gltf/foo.glb")
+            .unwrap();
+        assert_eq!(mockup.shading_map, Some("foo.shading.png".to_string()));
+        assert_eq!(mockup.displacement, 6.);
+        assert_eq!(mockup.shading, 1.);
+        assert!(config.models.mockup("bar.glb").is_none());
+        assert!(config.health.canary);
+        assert_eq!(config.health.canary_interval_secs, 30);
+        assert_eq!(config.health.heartbeat_timeout_secs, 60);
+        assert!(config.cache.enabled);
+        assert_eq!(config.cache.memory_max_bytes, 1048576);
+        assert_eq!(
+            config.cache.disk_dir,
+            Some("/var/cache/gimme-3d".to_string())
+        );
+        assert!(config.prefetch.enabled);
+        assert_eq!(config.prefetch.concurrency, 4);
+        assert_eq!(config.prefetch.ready_ratio, 0.5);
+        assert_eq!(config.jobs.max_jobs, 32);
+        assert_eq!(config.jobs.callback_hosts, vec!["hooks.example.com"]);
+        assert!(!config.output.webp_lossless);
+        assert_eq!(config.output.webp_quality, 75);
+        assert_eq!(config.output.webp_alpha_quality, 100);
+        assert_eq!(config.antialias.filter, antialias::Filter::Lanczos3);
+        assert_eq!(config.antialias.msaa, 4);
+        assert_eq!(config.antialias.max_supersample, 4);
+        assert_eq!(config.limits.max_width, 2048);
+        assert_eq!(config.limits.max_height, 4096);
+        assert_eq!(config.limits.max_texture_sets, 100);
+        assert_eq!(
+            config.storage.results_url,
+            Some("s3://results/renders/".to_string())
+        );
+        assert_eq!(
+            config.storage.s3.endpoint,
+            Some("http://localhost:9000".to_string())
+        );
+        assert!(config.storage.s3.path_style);
+        assert_eq!(config.storage.s3.buckets, vec!["textures"]);
+        assert_eq!(config.storage.s3.region, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_reports_location() {
+        let config = "port = 3030\nupscale_factor = \"two\"\n".to_string();
+        let e = Config::parse(config).err().unwrap();
+        assert!(e.to_string().contains("line 2"), "{}", e);
+
+        let no_vars = || std::iter::empty::<(String, String)>();
+        assert!(Config::load_with(Path::new("does-not-exist.toml"), no_vars()).is_ok());
+
+        let path = std::env::temp_dir().join(format!("gimme-3d-msaa-{}.toml", std::process::id()));
+        std::fs::write(&path, "[antialias]\nmsaa = 3\n").unwrap();
+        let e = Config::load_with(&path, no_vars()).err().unwrap();
+        assert!(format!("{:#}", e).contains("power of two"), "{:#}", e);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_overrides() -> Result<()> {
+        let vars = [
+            ("GIMME3D_PORT", "8080"),
+            ("GIMME3D_LIMITS__MAX_WIDTH", "1024"),
+            ("GIMME3D_MODELS__MODELS", r#"["a.glb", "b.glb"]"#),
+            (
+                "GIMME3D_MODELS__MODELS_BASE_URL",
+                "https://example.com/gltf/",
+            ),
+            ("GIMME3D_MODELS__LOCAL_MODEL_DIR", "123"),
+            ("GIMME3D_ANTIALIAS__FILTER", "triangle"),
+            ("PATH", "/usr/bin"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+
+        let config = Config::default().with_overrides(vars)?;
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.limits.max_width, 1024);
+        assert_eq!(config.models.models, vec!["a.glb", "b.glb"]);
+        assert_eq!(config.models.models_base_url, "https://example.com/gltf/");
+        assert_eq!(config.models.local_model_dir, "123");
+        assert_eq!(config.antialias.filter, antialias::Filter::Triangle);
+
+        let invalid = |name: &str, value: &str| {
+            let vars = [(name.to_string(), value.to_string())];
+            Config::default()
+                .with_overrides(vars)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(invalid("GIMME3D_PORTT", "1"), "invalid GIMME3D_PORTT");
+        assert_eq!(invalid("GIMME3D_PORT", "abc"), "invalid GIMME3D_PORT");
+        assert_eq!(invalid("GIMME3D_PORT__X", "1"), "invalid GIMME3D_PORT__X");
 
         Ok(())
     }
+
+    #[test]
+    fn test_output_options() {
+        let defaults = Output::default();
+
+        let options = defaults.options(Format::Webp, None, None, None);
+        assert!(options.lossless);
+
+        let options = defaults.options(Format::Webp, Some(60), None, None);
+        assert!(!options.lossless);
+        assert_eq!(options.quality, Some(60));
+        assert_eq!(options.alpha_quality, Some(100));
+
+        let lossy = Output {
+            webp_lossless: false,
+            ..Default::default()
+        };
+        let options = lossy.options(Format::Webp, None, Some(50), None);
+        assert!(!options.lossless);
+        assert_eq!(options.quality, Some(80));
+        assert_eq!(options.alpha_quality, Some(50));
+
+        let options = lossy.options(Format::Jpeg, None, None, Some(true));
+        assert!(!options.lossless);
+        assert_eq!(options.quality, None);
+    }
+
+    #[test]
+    fn test_antialias() {
+        let config = Config::default();
+
+        let settings = config.antialias(None, None);
+        assert_eq!(settings, antialias::Settings::default());
+
+        let settings = config.antialias(Some(16), Some(antialias::Filter::Triangle));
+        assert_eq!(settings.supersample, 4);
+        assert_eq!(settings.filter, antialias::Filter::Triangle);
+
+        let settings = config.antialias(Some(0), None);
+        assert_eq!(settings.supersample, 1);
+    }
+
+    #[test]
+    fn test_check_callback_url() {
+        let mut jobs = Jobs::default();
+        assert!(jobs
+            .check_callback_url("https://hooks.example.com/done")
+            .is_err());
+
+        jobs.callback_hosts = vec!["hooks.example.com".to_string()];
+        assert!(jobs
+            .check_callback_url("https://hooks.example.com/done")
+            .is_ok());
+        assert!(jobs
+            .check_callback_url("http://HOOKS.example.com:8080/done")
+            .is_ok());
+        for url in [
+            "http://169.254.169.254/latest/meta-data",
+            "https://hooks.example.com.evil.com/done",
+            "https://evil.com/?hooks.example.com",
+            "ftp://hooks.example.com/done",
+            "not a url",
+        ] {
+            assert!(matches!(
+                jobs.check_callback_url(url),
+                Err(Error::ForbiddenCallback(_))
+            ));
+        }
+    }
 }
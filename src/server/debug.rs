@@ -10,8 +10,12 @@ use warp::multipart::FormData;
 use warp::reply::Response;
 use warp::Filter;
 
+use crate::composite::{self, BlendMode};
+use crate::output::{self, Format};
 use crate::server::request::{ClientError, Request};
-use crate::server::server::ResultChannel;
+use crate::server::server::{unavailable, ResultChannel};
+use crate::server::shutdown::Shutdown;
+use crate::server::{config, reload};
 
 pub fn get() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::get().and(warp::path("gimme-3d")).map(|| {
@@ -42,6 +46,8 @@ const MAX_WIDTH: u32 = 2000;
 
 pub fn post(
     request_tx: mpsc::Sender<(Request, ResultChannel)>,
+    shared: Arc<reload::Shared>,
+    shutdown: Arc<Shutdown>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     let semaphore = Arc::new(Semaphore::new(1));
     warp::post()
@@ -49,16 +55,24 @@ pub fn post(
         .and(warp::multipart::form().max_length(Some(1024 * 1024 * 1024)))
         .and(warp::any().map(move || request_tx.clone()))
         .and(warp::any().map(move || semaphore.clone()))
+        .and(warp::any().map(move || shared.get()))
+        .and(warp::any().map(move || shutdown.clone()))
         .and_then(
             |form: FormData,
              request_tx: mpsc::Sender<(Request, ResultChannel)>,
-             sem: Arc<Semaphore>| async move {
+             sem: Arc<Semaphore>,
+             config: Arc<config::Config>,
+             shutdown: Arc<Shutdown>| async move {
+                if shutdown.is_draining() {
+                    return Ok(unavailable());
+                }
+
                 let request_future = DebugRequest::from_form_data(form).await;
                 let r = request_future.unwrap();
 
                 let (response_tx, response_rx) = oneshot::channel();
 
-                let mut mask_image = if let Ok(mask) = image::load_from_memory(&r.mask) {
+                let mask_image = if let Ok(mask) = image::load_from_memory(&r.mask) {
                     mask
                 } else {
                     return Err(warp::reject::Rejection::from(InternalServerError(
@@ -68,21 +82,17 @@ pub fn post(
 
                 let mut request: Request = r.clone().into();
 
-                if mask_image.width() > MAX_WIDTH {
-                    let ratio = mask_image.width() as f32 / mask_image.height() as f32;
-                    request.width = MAX_WIDTH;
-                    request.height = (MAX_WIDTH as f32 / ratio) as u32;
-
-                    mask_image = mask_image.thumbnail_exact(request.width, request.height);
-                } else {
-                    request.width = mask_image.width();
-                    request.height = mask_image.height();
-                }
+                (request.width, request.height) =
+                    composite::fit(mask_image.width(), mask_image.height(), MAX_WIDTH, u32::MAX);
+                let mask_image = mask_image.thumbnail_exact(request.width, request.height);
 
                 if r.texture.is_none() {
                     let texture_bytes = std::fs::read("testdata/canvas.png").unwrap();
                     request.textures = Some(vec![texture_bytes]);
                 }
+                if let Err(e) = request.load_layer(&config).await {
+                    return Err(warp::reject::Rejection::from(InternalServerError(e)));
+                }
 
                 let _ = sem.acquire_owned().await.unwrap();
                 request_tx.try_send((request, response_tx)).unwrap();
@@ -94,24 +104,25 @@ pub fn post(
                     }
                 };
 
-                let pixels = pixels.thumbnail_exact(mask_image.width(), mask_image.height());
-                image::imageops::overlay(&mut mask_image, &pixels, 0, 0);
+                let result = composite::blend(&mask_image, &pixels, BlendMode::Normal);
 
-                respond(mask_image)
+                let options = config.output.options(Format::Webp, None, None, None);
+                respond(result, &options)
             },
         )
 }
 
-fn respond(result: DynamicImage) -> anyhow::Result<Response, warp::Rejection> {
-    let mut writer = std::io::Cursor::new(Vec::new());
-    result
-        .write_to(&mut writer, image::ImageOutputFormat::WebP)
-        .unwrap();
+fn respond(
+    result: DynamicImage,
+    options: &output::Options,
+) -> anyhow::Result<Response, warp::Rejection> {
+    let body = output::encode(&result, Format::Webp, options)
+        .map_err(|e| warp::reject::Rejection::from(InternalServerError(e)))?;
 
     Ok::<Response, warp::Rejection>(
         warp::http::response::Builder::new()
-            .header("Content-Type", "image/webp")
-            .body(writer.into_inner().into())
+            .header("Content-Type", Format::Webp.content_type())
+            .body(body.into())
             .unwrap(),
     )
 }
@@ -123,11 +134,11 @@ struct DebugRequest {
     texture: Option<Vec<u8>>,
 }
 
-impl Into<Request> for DebugRequest {
-    fn into(self) -> Request {
-        let textures = self.texture.map(|texture| vec![texture]);
+impl From<DebugRequest> for Request {
+    fn from(r: DebugRequest) -> Self {
+        let textures = r.texture.map(|texture| vec![texture]);
         Request {
-            model: Some(self.model),
+            model: Some(r.model),
             textures,
             ..Default::default()
         }
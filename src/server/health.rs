@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+use super::shutdown::Shutdown;
+use super::{config, prefetch, reload};
+
+/// Liveness signals reported by the render loop.
+pub struct State {
+    started: Instant,
+    heartbeat_ms: AtomicU64,
+    canary: Mutex<Option<Result<(), String>>>,
+}
+
+impl State {
+    pub fn new_arc() -> Arc<Self> {
+        Arc::new(State {
+            started: Instant::now(),
+            heartbeat_ms: AtomicU64::new(0),
+            canary: Mutex::new(None),
+        })
+    }
+
+    pub fn beat(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.heartbeat_ms.store(elapsed, Ordering::SeqCst);
+    }
+
+    pub fn heartbeat_age(&self) -> Duration {
+        let last = Duration::from_millis(self.heartbeat_ms.load(Ordering::SeqCst));
+        self.started.elapsed().saturating_sub(last)
+    }
+
+    pub fn set_canary(&self, result: &anyhow::Result<()>) {
+        let result = result.as_ref().map(|_| ()).map_err(|e| e.to_string());
+        *self.canary.lock().unwrap() = Some(result);
+    }
+
+    fn canary(&self) -> Option<Result<(), String>> {
+        self.canary.lock().unwrap().clone()
+    }
+}
+
+#[derive(Serialize)]
+struct Report {
+    status: &'static str,
+    checks: BTreeMap<&'static str, String>,
+}
+
+impl Report {
+    fn new() -> Self {
+        Report {
+            status: "ok",
+            checks: BTreeMap::new(),
+        }
+    }
+
+    fn check(&mut self, name: &'static str, result: Result<(), String>) {
+        match result {
+            Ok(()) => {
+                self.checks.insert(name, "ok".to_string());
+            }
+            Err(e) => {
+                self.status = "failed";
+                self.checks.insert(name, e);
+            }
+        }
+    }
+
+    fn into_response(self) -> Response {
+        let status = if self.status == "ok" {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        warp::reply::with_status(warp::reply::json(&self), status).into_response()
+    }
+}
+
+fn check_render_loop(state: &State, timeout: Duration) -> Result<(), String> {
+    let age = state.heartbeat_age();
+    if age > timeout {
+        return Err(format!("no heartbeat for {:?}", age));
+    }
+    Ok(())
+}
+
+fn check_model_dir(local_model_dir: &str) -> Result<(), String> {
+    std::fs::read_dir(local_model_dir)
+        .map(|_| ())
+        .map_err(|e| format!("{}: {}", local_model_dir, e))
+}
+
+/// GET `/livez`, fails only when the render loop stopped beating.
+pub fn livez(
+    state: Arc<State>,
+    settings: config::Health,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let timeout = Duration::from_secs(settings.heartbeat_timeout_secs);
+    warp::get().and(warp::path("livez")).map(move || {
+        let mut report = Report::new();
+        report.check("render_loop", check_render_loop(&state, timeout));
+        report.into_response()
+    })
+}
+
+/// GET `/readyz`, fails while draining or when any dependency of a render is broken.
+/// The model directory is checked as of the current config, `settings` need a restart.
+pub fn readyz(
+    state: Arc<State>,
+    shutdown: Arc<Shutdown>,
+    settings: config::Health,
+    shared: Arc<reload::Shared>,
+    prefetch: Option<Arc<prefetch::State>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let timeout = Duration::from_secs(settings.heartbeat_timeout_secs);
+    warp::get().and(warp::path("readyz")).map(move || {
+        let mut report = Report::new();
+
+        if shutdown.is_draining() {
+            report.check("shutdown", Err("draining".to_string()));
+        }
+
+        report.check("render_loop", check_render_loop(&state, timeout));
+        let config = shared.get();
+        report.check("model_dir", check_model_dir(&config.models.local_model_dir));
+
+        if let Some(prefetch) = &prefetch {
+            report.check("prefetch", prefetch.check());
+        }
+
+        if settings.canary {
+            let canary = state
+                .canary()
+                .unwrap_or_else(|| Err("not run yet".to_string()));
+            report.check("canary", canary);
+        }
+
+        report.into_response()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_loop_check() {
+        let state = State::new_arc();
+        state.beat();
+        assert!(check_render_loop(&state, Duration::from_secs(5)).is_ok());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(check_render_loop(&state, Duration::from_millis(10)).is_err());
+    }
+
+    #[test]
+    fn test_report_status() {
+        let mut report = Report::new();
+        report.check("model_dir", check_model_dir("testdata"));
+        assert_eq!(report.status, "ok");
+
+        report.check("model_dir", check_model_dir("does-not-exist"));
+        assert_eq!(report.status, "failed");
+    }
+}
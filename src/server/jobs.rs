@@ -0,0 +1,530 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Semaphore};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+use crate::mockup::MapCache;
+use crate::output::Format;
+use crate::storage;
+
+use super::request::Request;
+use super::server::{dispatch, encode, failed, load_mask, rejected, Mask, ResultChannel};
+use super::shutdown::Shutdown;
+use super::{accept, config, reload};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JobStatus {
+    pub id: String,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_url: Option<String>,
+    /// Where the result was written with `storage.results_url` set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stored_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JobRequest {
+    #[serde(flatten)]
+    request: Request,
+    /// Receives a POST with the final `JobStatus` once the job is done or failed,
+    /// its host has to be one of `[jobs] callback_hosts`.
+    callback_url: Option<String>,
+}
+
+struct Output {
+    body: Vec<u8>,
+    format: Format,
+    stored_url: Option<String>,
+}
+
+struct Job {
+    status: Status,
+    error: Option<String>,
+    output: Option<Output>,
+}
+
+impl Job {
+    fn is_finished(&self) -> bool {
+        matches!(self.status, Status::Done | Status::Failed)
+    }
+}
+
+/// Bounded in-memory store of jobs and their encoded results,
+/// the oldest finished jobs are evicted first.
+pub struct Store {
+    max_jobs: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    jobs: HashMap<String, Job>,
+    order: VecDeque<String>,
+}
+
+impl Store {
+    pub fn new_arc(max_jobs: usize) -> Arc<Self> {
+        Arc::new(Store {
+            max_jobs,
+            inner: Mutex::new(Inner::default()),
+        })
+    }
+
+    /// Registers a new queued job, returns `None` when the store is full of unfinished jobs.
+    fn insert(&self) -> Option<String> {
+        let mut inner = self.inner.lock().unwrap();
+
+        while inner.jobs.len() >= self.max_jobs {
+            let Inner { jobs, order } = &mut *inner;
+            let position = order.iter().position(|id| jobs[id].is_finished())?;
+            let id = order.remove(position)?;
+            jobs.remove(&id);
+        }
+
+        let id = uuid::Uuid::new_v4().to_string();
+        inner.jobs.insert(
+            id.clone(),
+            Job {
+                status: Status::Queued,
+                error: None,
+                output: None,
+            },
+        );
+        inner.order.push_back(id.clone());
+
+        Some(id)
+    }
+
+    fn set_running(&self, id: &str) {
+        if let Some(job) = self.inner.lock().unwrap().jobs.get_mut(id) {
+            job.status = Status::Running;
+        }
+    }
+
+    fn finish(&self, id: &str, result: Result<Output>) -> Option<JobStatus> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let job = inner.jobs.get_mut(id)?;
+            match result {
+                Ok(output) => {
+                    job.status = Status::Done;
+                    job.output = Some(output);
+                }
+                Err(e) => {
+                    job.status = Status::Failed;
+                    job.error = Some(e.to_string());
+                }
+            }
+        }
+
+        self.status(id)
+    }
+
+    pub fn status(&self, id: &str) -> Option<JobStatus> {
+        let inner = self.inner.lock().unwrap();
+        let job = inner.jobs.get(id)?;
+
+        Some(JobStatus {
+            id: id.to_string(),
+            status: job.status,
+            error: job.error.clone(),
+            result_url: (job.status == Status::Done).then(|| format!("/jobs/{}/result", id)),
+            stored_url: job
+                .output
+                .as_ref()
+                .and_then(|output| output.stored_url.clone()),
+        })
+    }
+
+    /// Number of jobs that are queued or running.
+    pub fn pending(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner.jobs.values().filter(|job| !job.is_finished()).count()
+    }
+
+    /// Resolves once every accepted job has finished.
+    pub async fn drained(&self) {
+        while self.pending() > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    fn result(&self, id: &str) -> Option<Response> {
+        let inner = self.inner.lock().unwrap();
+        let job = inner.jobs.get(id)?;
+
+        let response = match (&job.output, job.status) {
+            (Some(output), _) => warp::http::response::Builder::new()
+                .header("Content-Type", output.format.content_type())
+                .header("Vary", "Accept")
+                .body(output.body.clone().into())
+                .unwrap(),
+            (None, Status::Failed) => warp::reply::with_status(
+                job.error.clone().unwrap_or_default(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response(),
+            (None, _) => warp::reply::with_status("job is not finished", StatusCode::CONFLICT)
+                .into_response(),
+        };
+
+        Some(response)
+    }
+}
+
+/// POST `/jobs`, GET `/jobs/{id}` and GET `/jobs/{id}/result`.
+pub fn routes(
+    store: Arc<Store>,
+    sem: Arc<Semaphore>,
+    request_tx: mpsc::Sender<(Request, ResultChannel)>,
+    shutdown: Arc<Shutdown>,
+    shared: Arc<reload::Shared>,
+    maps: Arc<MapCache>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(shared.get().jobs.callback_timeout_secs))
+        // a redirect could lead the callback to a host that is not allowed
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("http client can be built");
+
+    let create_store = store.clone();
+    let create = warp::post()
+        .and(warp::path!("jobs"))
+        .and(warp::body::json())
+        .and(warp::header::optional("accept"))
+        .and_then(move |mut job: JobRequest, accept_header: Option<String>| {
+            let store = create_store.clone();
+            let sem = sem.clone();
+            let request_tx = request_tx.clone();
+            let shutdown = shutdown.clone();
+            let client = client.clone();
+            let maps = maps.clone();
+            let config = shared.get();
+            async move {
+                if shutdown.is_draining() {
+                    return Ok::<Response, warp::Rejection>(
+                        warp::reply::with_status("shutting down", StatusCode::SERVICE_UNAVAILABLE)
+                            .into_response(),
+                    );
+                }
+
+                if let Err(e) = job.request.resolve_product(&config) {
+                    return failed(e);
+                }
+
+                if let Some(callback_url) = &job.callback_url {
+                    if let Err(e) = config.jobs.check_callback_url(callback_url) {
+                        return Ok(warp::reply::with_status(
+                            e.to_string(),
+                            StatusCode::UNPROCESSABLE_ENTITY,
+                        )
+                        .into_response());
+                    }
+                }
+
+                // the size of a mask is only known once it is loaded by the job
+                let request = &job.request;
+                if !request.has_mask() {
+                    if let Err(e) =
+                        config.check_limits(request.width, request.height, request.supersample)
+                    {
+                        return Ok(rejected(&e));
+                    }
+                }
+
+                let Some(id) = store.insert() else {
+                    return Ok(warp::reply::with_status(
+                        "too many unfinished jobs",
+                        StatusCode::SERVICE_UNAVAILABLE,
+                    )
+                    .into_response());
+                };
+
+                let status = store.status(&id);
+                let format = job
+                    .request
+                    .format
+                    .unwrap_or_else(|| accept::negotiate(accept_header.as_deref()));
+
+                tokio::spawn(run(
+                    id.clone(),
+                    job,
+                    format,
+                    config,
+                    store,
+                    sem,
+                    request_tx,
+                    client,
+                    maps,
+                ));
+
+                Ok(warp::reply::with_header(
+                    warp::reply::with_status(warp::reply::json(&status), StatusCode::ACCEPTED),
+                    "Location",
+                    format!("/jobs/{}", id),
+                )
+                .into_response())
+            }
+        });
+
+    let status_store = store.clone();
+    let status = warp::get()
+        .and(warp::path!("jobs" / String))
+        .map(move |id: String| match status_store.status(&id) {
+            Some(status) => warp::reply::json(&status).into_response(),
+            None => not_found(),
+        });
+
+    let result = warp::get()
+        .and(warp::path!("jobs" / String / "result"))
+        .map(move |id: String| store.result(&id).unwrap_or_else(not_found));
+
+    create.or(status).or(result)
+}
+
+fn not_found() -> Response {
+    warp::reply::with_status("job not found", StatusCode::NOT_FOUND).into_response()
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run(
+    id: String,
+    job: JobRequest,
+    format: Format,
+    config: Arc<config::Config>,
+    store: Arc<Store>,
+    sem: Arc<Semaphore>,
+    request_tx: mpsc::Sender<(Request, ResultChannel)>,
+    client: reqwest::Client,
+    maps: Arc<MapCache>,
+) {
+    let start = std::time::Instant::now();
+
+    let request = job.request;
+    let options = config.output.options(
+        format,
+        request.quality,
+        request.alpha_quality,
+        request.lossless,
+    );
+
+    let result = render(&id, request, &config, &maps, &store, &sem, &request_tx).await;
+
+    let result = result
+        .and_then(|(pixels, mask)| match mask {
+            Some(mask) => encode(format, options, mask.apply(pixels)),
+            None => encode(format, options, pixels),
+        })
+        .map(|body| Output {
+            body,
+            format,
+            stored_url: None,
+        });
+    let result = match (result, &config.storage.results_url) {
+        (Ok(output), Some(results_url)) => store_result(&id, output, results_url, &config).await,
+        (result, _) => result,
+    };
+
+    if let Err(e) = &result {
+        log::error!("Job {} failed: {}", id, e);
+    }
+    log::info!("Job {} time overall: {:?}", id, start.elapsed());
+
+    let Some(status) = store.finish(&id, result) else {
+        return;
+    };
+
+    if let Some(callback_url) = job.callback_url {
+        if let Err(e) = notify(&client, &callback_url, &status).await {
+            log::error!("Job {} callback failed: {}", id, e);
+        }
+    }
+}
+
+/// Writes the encoded result to `{results_url}{id}.{extension}`, a failed write fails the job.
+async fn store_result(
+    id: &str,
+    mut output: Output,
+    results_url: &str,
+    config: &config::Config,
+) -> Result<Output> {
+    let separator = if results_url.ends_with('/') { "" } else { "/" };
+    let location = format!(
+        "{}{}{}.{}",
+        results_url,
+        separator,
+        id,
+        output.format.extension()
+    );
+
+    storage::put(
+        &location,
+        output.body.clone(),
+        output.format.content_type(),
+        &config.storage,
+    )
+    .await
+    .map_err(|e| e.context("storing result"))?;
+
+    output.stored_url = Some(location);
+    Ok(output)
+}
+
+/// Loads the mask of the request and renders it once the render slot is free.
+async fn render(
+    id: &str,
+    mut request: Request,
+    config: &config::Config,
+    maps: &MapCache,
+    store: &Store,
+    sem: &Semaphore,
+    request_tx: &mpsc::Sender<(Request, ResultChannel)>,
+) -> Result<(DynamicImage, Option<Mask>)> {
+    let mask = load_mask(&mut request, config, maps).await?;
+    if mask.is_some() {
+        config.check_limits(request.width, request.height, request.supersample)?;
+    }
+    request.load_layer(config).await?;
+
+    let _permit = sem.acquire().await?;
+    store.set_running(id);
+    let pixels = dispatch(request, request_tx).await?;
+
+    Ok((pixels, mask))
+}
+
+/// Posts the job status to the callback url given when the job was created.
+pub async fn notify(client: &reqwest::Client, url: &str, status: &JobStatus) -> Result<()> {
+    let response = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(status)?)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(anyhow!("{} responded with {}", url, response.status()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output() -> Result<Output> {
+        Ok(Output {
+            body: vec![1, 2, 3],
+            format: Format::Png,
+            stored_url: None,
+        })
+    }
+
+    #[test]
+    fn test_store_evicts_finished_jobs() {
+        let store = Store::new_arc(2);
+
+        let first = store.insert().unwrap();
+        let second = store.insert().unwrap();
+        assert!(store.insert().is_none());
+
+        store.finish(&first, output());
+        assert_eq!(store.pending(), 1);
+
+        let third = store.insert().unwrap();
+        assert!(store.status(&first).is_none());
+        assert_eq!(store.status(&second).unwrap().status, Status::Queued);
+        assert_eq!(store.status(&third).unwrap().status, Status::Queued);
+    }
+
+    #[test]
+    fn test_store_status() {
+        let store = Store::new_arc(2);
+
+        let id = store.insert().unwrap();
+        store.set_running(&id);
+        assert_eq!(store.status(&id).unwrap().status, Status::Running);
+
+        let status = store.finish(&id, output()).unwrap();
+        assert_eq!(status.status, Status::Done);
+        assert_eq!(status.result_url, Some(format!("/jobs/{}/result", id)));
+
+        let id = store.insert().unwrap();
+        let status = store.finish(&id, Err(anyhow!("no camera"))).unwrap();
+        assert_eq!(status.status, Status::Failed);
+        assert_eq!(status.error, Some("no camera".to_string()));
+        assert!(status.result_url.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_store_result() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("gimme-3d-results-{}", std::process::id()));
+        let results_url = dir.to_string_lossy().to_string();
+
+        let output =
+            store_result("123", output()?, &results_url, &config::Config::default()).await?;
+        let location = dir.join("123.png");
+        assert_eq!(
+            output.stored_url,
+            Some(location.to_string_lossy().to_string())
+        );
+        assert_eq!(std::fs::read(&location)?, vec![1, 2, 3]);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_notify() -> Result<()> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let stub = warp::post()
+            .and(warp::path("callback"))
+            .and(warp::body::json())
+            .map(move |status: JobStatus| {
+                tx.send(status).unwrap();
+                "ok"
+            });
+        let (addr, server) = warp::serve(stub).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let status = JobStatus {
+            id: "123".to_string(),
+            status: Status::Done,
+            error: None,
+            result_url: Some("/jobs/123/result".to_string()),
+            stored_url: None,
+        };
+
+        let url = format!("http://{}/callback", addr);
+        notify(&reqwest::Client::new(), &url, &status).await?;
+        assert_eq!(rx.recv().await.as_ref(), Some(&status));
+
+        let url = format!("http://{}/missing", addr);
+        assert!(notify(&reqwest::Client::new(), &url, &status)
+            .await
+            .is_err());
+
+        Ok(())
+    }
+}
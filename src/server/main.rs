@@ -2,5 +2,8 @@ use gimme_3d::server::server;
 
 #[tokio::main]
 async fn main() {
-    server::run().await;
+    if let Err(e) = server::run("config.toml").await {
+        eprintln!("Error: {:#}", e);
+        std::process::exit(1);
+    }
 }
@@ -1,15 +1,13 @@
 use std::sync::Arc;
 
-use prometheus::{
-    HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
-};
-use warp::{Rejection, Reply};
+use prometheus::{HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry};
+use warp::{Filter, Rejection, Reply};
 
 pub struct Metrics {
     incoming_requests: IntCounter,
-    connected_clients: IntGauge,
     response_code_collector: IntCounterVec,
     response_time_collector: HistogramVec,
+    cache_lookups: IntCounterVec,
     registry: Registry,
 }
 
@@ -17,16 +15,19 @@ impl Metrics {
     pub fn new_arc() -> Arc<Self> {
         let incoming_requests = IntCounter::new("incoming_requests", "Incoming Requests")
             .expect("metric can be created");
-        let connected_clients =
-            IntGauge::new("connected_clients", "Connected Clients").expect("metric can be created");
         let response_code_collector = IntCounterVec::new(
             Opts::new("response_code", "Response Codes"),
-            &["env", "statuscode", "type"],
+            &["statuscode", "type"],
         )
         .expect("metric can be created");
         let response_time_collector = HistogramVec::new(
             HistogramOpts::new("response_time", "Response Times"),
-            &["env"],
+            &["path"],
+        )
+        .expect("metric can be created");
+        let cache_lookups = IntCounterVec::new(
+            Opts::new("render_cache_lookups", "Render Cache Lookups"),
+            &["result"],
         )
         .expect("metric can be created");
 
@@ -35,25 +36,54 @@ impl Metrics {
         registry
             .register(Box::new(incoming_requests.clone()))
             .expect("collector can be registered");
-        registry
-            .register(Box::new(connected_clients.clone()))
-            .expect("collector can be registered");
         registry
             .register(Box::new(response_code_collector.clone()))
             .expect("collector can be registered");
         registry
             .register(Box::new(response_time_collector.clone()))
             .expect("collector can be registered");
+        registry
+            .register(Box::new(cache_lookups.clone()))
+            .expect("collector can be registered");
 
         Arc::new(Metrics {
             incoming_requests,
-            connected_clients,
             response_code_collector,
             response_time_collector,
+            cache_lookups,
             registry,
         })
     }
 
+    /// Records a response, used with `warp::log::custom`.
+    pub fn track(&self, info: warp::log::Info) {
+        self.incoming_requests.inc();
+
+        let status = info.status();
+        let kind = if status.is_success() || status.is_redirection() {
+            "success"
+        } else if status.is_client_error() {
+            "client_error"
+        } else {
+            "server_error"
+        };
+        self.response_code_collector
+            .with_label_values(&[status.as_str(), kind])
+            .inc();
+
+        // ids in paths would make a series per job or model
+        let path = info.path().split('/').nth(1).unwrap_or_default();
+        self.response_time_collector
+            .with_label_values(&[path])
+            .observe(info.elapsed().as_secs_f64());
+    }
+
+    /// Counts a lookup of the render cache, `result` is the tier of a hit,
+    /// `not_modified` or `miss`.
+    pub fn cache_lookup(&self, result: &str) {
+        self.cache_lookups.with_label_values(&[result]).inc();
+    }
+
     async fn metrics_handler(m: Arc<Metrics>) -> Result<impl Reply, Rejection> {
         use prometheus::Encoder;
         let encoder = prometheus::TextEncoder::new();
@@ -81,4 +111,14 @@ impl Metrics {
         res.push_str(&res_custom);
         Ok(res)
     }
+
+    /// GET `/metrics` in the prometheus text format.
+    pub fn route(
+        m: Arc<Metrics>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path("metrics"))
+            .and(warp::any().map(move || m.clone()))
+            .and_then(Self::metrics_handler)
+    }
 }
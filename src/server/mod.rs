@@ -2,8 +2,19 @@ pub use server::run;
 
 pub use crate::render;
 
+mod accept;
+mod batch;
+mod cache;
 pub mod config;
 mod debug;
+mod health;
+mod jobs;
 mod logger;
+mod metrics;
+mod models;
+mod prefetch;
+mod reload;
 mod request;
+#[allow(clippy::module_inception)]
 pub mod server;
+mod shutdown;
@@ -0,0 +1,250 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+use crate::gltf::{extract_all, get_camera, get_mesh, scene_index};
+use crate::manifest::{self, Manifest};
+
+use super::{config, reload};
+
+/// What the server knows about a model, the id is the file name without `.glb`.
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    pub id: String,
+    pub file_name: String,
+    /// Remote url of a model listed in the config.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Listed in `models` of the config.
+    pub configured: bool,
+    /// Present in the local model directory.
+    pub cached: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub has_manifest: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meshes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cameras: Option<usize>,
+    /// Problems that keep the model from rendering, an empty list for a deployable model.
+    pub errors: Vec<String>,
+}
+
+/// A model with the names of its meshes and cameras and its manifest.
+#[derive(Serialize, Debug)]
+pub struct Details {
+    #[serde(flatten)]
+    pub summary: Summary,
+    pub mesh_names: Vec<String>,
+    pub camera_names: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<Manifest>,
+}
+
+/// File names of the models listed in the config and of the glbs in the local model directory.
+pub fn known(models: &config::Models) -> Vec<String> {
+    let mut file_names: BTreeSet<String> = models.models.iter().cloned().collect();
+
+    if let Ok(entries) = std::fs::read_dir(&models.local_model_dir) {
+        file_names.extend(
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|file_name| file_name.ends_with(".glb")),
+        );
+    }
+
+    file_names.into_iter().collect()
+}
+
+/// Inspects the local copy of a model and its manifest.
+pub fn inspect(models: &config::Models, file_name: &str) -> Details {
+    let path = Path::new(&models.local_model_dir).join(file_name);
+    let size = std::fs::metadata(&path).ok().map(|metadata| metadata.len());
+    let configured = models.models.iter().any(|model| model == file_name);
+
+    let mut errors = vec![];
+    let (mesh_names, camera_names) = match size {
+        Some(_) => read_nodes(&path).unwrap_or_else(|e| {
+            errors.push(e.to_string());
+            (vec![], vec![])
+        }),
+        None => {
+            errors.push("not cached locally".to_string());
+            (vec![], vec![])
+        }
+    };
+    let parsed = size.is_some() && errors.is_empty();
+
+    let manifest = Manifest::load(&path).unwrap_or_else(|e| {
+        errors.push(format!("{:#}", e));
+        None
+    });
+
+    Details {
+        summary: Summary {
+            id: file_name.trim_end_matches(".glb").to_string(),
+            file_name: file_name.to_string(),
+            url: configured.then(|| format!("{}{}", models.models_base_url, file_name)),
+            configured,
+            cached: size.is_some(),
+            size,
+            name: manifest.as_ref().and_then(|manifest| manifest.name.clone()),
+            has_manifest: manifest::sidecar(&path).is_some(),
+            meshes: parsed.then_some(mesh_names.len()),
+            cameras: parsed.then_some(camera_names.len()),
+            errors,
+        },
+        mesh_names,
+        camera_names,
+        manifest,
+    }
+}
+
+/// Names of the meshes and cameras of the scene a request without `scene` renders,
+/// the default scene or else the first one.
+fn read_nodes(path: &Path) -> Result<(Vec<String>, Vec<String>)> {
+    let gltf = gltf::Gltf::open(path)?;
+    let index = scene_index(&gltf.document, None)?;
+    let scene = gltf
+        .document
+        .scenes()
+        .nth(index)
+        .expect("scene_index returns an existing scene");
+
+    let unnamed = || "unnamed".to_string();
+    let meshes: Vec<String> = extract_all(&scene, get_mesh)
+        .into_iter()
+        .map(|mesh| mesh.name.unwrap_or_else(unnamed))
+        .collect();
+    let cameras: Vec<String> = extract_all(&scene, get_camera)
+        .into_iter()
+        .map(|camera| camera.name.unwrap_or_else(unnamed))
+        .collect();
+
+    if meshes.is_empty() {
+        return Err(anyhow!("no mesh"));
+    }
+    if cameras.is_empty() {
+        return Err(anyhow!("no camera"));
+    }
+
+    Ok((meshes, cameras))
+}
+
+/// GET `/models` and GET `/models/{id}`.
+pub fn routes(
+    shared: Arc<reload::Shared>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let list_shared = shared.clone();
+    let list = warp::get().and(warp::path!("models")).and_then(move || {
+        let models = list_shared.get().models.clone();
+        async move {
+            let summaries = tokio::task::spawn_blocking(move || {
+                known(&models)
+                    .iter()
+                    .map(|file_name| inspect(&models, file_name).summary)
+                    .collect::<Vec<_>>()
+            })
+            .await;
+
+            Ok::<Response, warp::Rejection>(match summaries {
+                Ok(summaries) => warp::reply::json(&summaries).into_response(),
+                Err(e) => internal_error(e.into()),
+            })
+        }
+    });
+
+    let get = warp::get()
+        .and(warp::path!("models" / String))
+        .and_then(move |id: String| {
+            let models = shared.get().models.clone();
+            async move {
+                let file_name = format!("{}.glb", id);
+                if !known(&models).contains(&file_name) {
+                    return Ok::<Response, warp::Rejection>(
+                        warp::reply::with_status("model not found", StatusCode::NOT_FOUND)
+                            .into_response(),
+                    );
+                }
+
+                let details =
+                    tokio::task::spawn_blocking(move || inspect(&models, &file_name)).await;
+
+                Ok(match details {
+                    Ok(details) => warp::reply::json(&details).into_response(),
+                    Err(e) => internal_error(e.into()),
+                })
+            }
+        });
+
+    list.or(get)
+}
+
+fn internal_error(e: anyhow::Error) -> Response {
+    log::error!("Error: {}", e);
+    warp::reply::with_status(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn models() -> config::Models {
+        config::Models {
+            local_model_dir: "testdata".to_string(),
+            models_base_url: "https://foobar.com/gltf/".to_string(),
+            models: vec!["canary.glb".to_string(), "missing.glb".to_string()],
+            mockups: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_known() {
+        assert_eq!(known(&models()), vec!["canary.glb", "missing.glb"]);
+    }
+
+    #[test]
+    fn test_inspect() {
+        let details = inspect(&models(), "canary.glb");
+        assert!(details.summary.cached);
+        assert_eq!(
+            details.summary.url,
+            Some("https://foobar.com/gltf/canary.glb".to_string())
+        );
+        assert_eq!(details.summary.meshes, Some(1));
+        assert_eq!(details.summary.cameras, Some(1));
+        assert!(details.summary.errors.is_empty());
+
+        let details = inspect(&models(), "missing.glb");
+        assert!(!details.summary.cached);
+        assert_eq!(details.summary.meshes, None);
+        assert_eq!(details.summary.errors, vec!["not cached locally"]);
+    }
+
+    #[test]
+    fn test_read_nodes_without_default_scene() -> Result<()> {
+        let bytes = std::fs::read("testdata/canary.glb")?;
+        let mut root = gltf::Gltf::from_slice(&bytes)?.document.into_json();
+        root.scene = None;
+        let mut glb = gltf::binary::Glb::from_slice(&bytes)?;
+        glb.json = std::borrow::Cow::Owned(root.to_vec()?);
+
+        let path = std::env::temp_dir().join(format!("gimme-3d-scenes-{}.glb", std::process::id()));
+        std::fs::write(&path, glb.to_vec()?)?;
+        let result = read_nodes(&path);
+        std::fs::remove_file(&path)?;
+
+        let (meshes, cameras) = result?;
+        assert_eq!((meshes.len(), cameras.len()), (1, 1));
+        Ok(())
+    }
+}
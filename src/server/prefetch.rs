@@ -0,0 +1,168 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use futures_util::{stream, StreamExt};
+
+use crate::storage;
+
+use super::config;
+
+/// Progress of the prefetch of the configured models.
+pub struct State {
+    total: usize,
+    ready_ratio: f64,
+    available: AtomicUsize,
+    finished: AtomicUsize,
+    failed: Mutex<Vec<String>>,
+}
+
+impl State {
+    pub fn new_arc(total: usize, ready_ratio: f64) -> Arc<Self> {
+        Arc::new(State {
+            total,
+            ready_ratio,
+            available: AtomicUsize::new(0),
+            finished: AtomicUsize::new(0),
+            failed: Mutex::new(vec![]),
+        })
+    }
+
+    fn record(&self, file_name: &str, result: Result<()>) {
+        match result {
+            Ok(()) => {
+                self.available.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(e) => {
+                log::error!("Could not prefetch {}: {:#}", file_name, e);
+                self.failed.lock().unwrap().push(file_name.to_string());
+            }
+        }
+        self.finished.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Ready once `ready_ratio` of the models are available or every model has been tried.
+    pub fn check(&self) -> Result<(), String> {
+        let available = self.available.load(Ordering::SeqCst);
+        let finished = self.finished.load(Ordering::SeqCst);
+        let required = (self.total as f64 * self.ready_ratio.clamp(0., 1.)).ceil() as usize;
+
+        if finished < self.total && available < required {
+            return Err(format!("{}/{} models prefetched", available, self.total));
+        }
+        Ok(())
+    }
+
+    pub fn failed(&self) -> Vec<String> {
+        self.failed.lock().unwrap().clone()
+    }
+}
+
+/// Downloads the models missing from the local model directory, a model is only
+/// written there once it parses as glTF, so a render never sees a partial or broken file.
+pub async fn run(
+    state: Arc<State>,
+    models: config::Models,
+    storage: storage::Settings,
+    concurrency: usize,
+) {
+    if let Err(e) = tokio::fs::create_dir_all(&models.local_model_dir).await {
+        log::error!("Could not create {}: {}", models.local_model_dir, e);
+    }
+
+    log::info!("Prefetching {} models", state.total);
+
+    stream::iter(models.models.iter())
+        .for_each_concurrent(concurrency.max(1), |file_name| {
+            let state = state.clone();
+            let models = &models;
+            let storage = &storage;
+            async move {
+                let result = fetch(models, storage, file_name).await;
+                state.record(file_name, result);
+            }
+        })
+        .await;
+
+    let failed = state.failed();
+    match failed.is_empty() {
+        true => log::info!("Prefetched {} models", state.total),
+        false => log::warn!(
+            "Prefetched {} of {} models, failed: {}",
+            state.total - failed.len(),
+            state.total,
+            failed.join(", ")
+        ),
+    }
+}
+
+async fn fetch(
+    models: &config::Models,
+    storage: &storage::Settings,
+    file_name: &str,
+) -> Result<()> {
+    let path = Path::new(&models.local_model_dir).join(file_name);
+
+    if let Ok(bytes) = tokio::fs::read(&path).await {
+        match validate(&bytes) {
+            Ok(()) => return Ok(()),
+            Err(e) => log::warn!("Replacing {}: {}", path.display(), e),
+        }
+    }
+
+    let url = format!("{}{}", models.models_base_url, file_name);
+    let bytes = storage::get(&url, storage).await?;
+    validate(&bytes).with_context(|| format!("{} is not a valid glTF", url))?;
+
+    // renaming within the directory is atomic
+    let partial = path.with_file_name(format!("{}.part", file_name));
+    tokio::fs::write(&partial, &bytes).await?;
+    tokio::fs::rename(&partial, &path).await?;
+
+    Ok(())
+}
+
+fn validate(bytes: &[u8]) -> Result<()> {
+    gltf::Gltf::from_slice(bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("gimme-3d-prefetch-{}", std::process::id()));
+        let models = config::Models {
+            local_model_dir: dir.to_string_lossy().to_string(),
+            models_base_url: "testdata/".to_string(),
+            models: vec!["canary.glb".to_string(), "test.png".to_string()],
+            ..Default::default()
+        };
+
+        let state = State::new_arc(models.models.len(), 1.);
+        assert!(state.check().is_err());
+
+        run(state.clone(), models, Default::default(), 2).await;
+        assert!(dir.join("canary.glb").is_file());
+        assert!(!dir.join("test.png").exists());
+        assert_eq!(state.failed(), vec!["test.png"]);
+        assert!(state.check().is_ok());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_check() {
+        let state = State::new_arc(4, 0.75);
+        state.record("a.glb", Ok(()));
+        state.record("b.glb", Ok(()));
+        assert_eq!(state.check(), Err("2/4 models prefetched".to_string()));
+
+        state.record("c.glb", Ok(()));
+        assert!(state.check().is_ok());
+    }
+}
@@ -0,0 +1,151 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+
+use super::config::Config;
+use super::shutdown::Shutdown;
+
+/// The current config, replaced on SIGHUP or when the file changes.
+/// Handlers take a snapshot per request, so a request sees a single config throughout.
+pub struct Shared {
+    path: PathBuf,
+    current: RwLock<Arc<Config>>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl Shared {
+    /// Loads the config, a malformed one is an error.
+    pub fn load_arc(path: impl Into<PathBuf>) -> Result<Arc<Self>> {
+        let path = path.into();
+        let modified = modified(&path);
+        let config = Config::load(&path)?;
+
+        Ok(Arc::new(Shared {
+            path,
+            current: RwLock::new(Arc::new(config)),
+            modified: RwLock::new(modified),
+        }))
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Reads the file again, a malformed config keeps the current one.
+    pub fn reload(&self) -> Result<()> {
+        *self.modified.write().unwrap() = modified(&self.path);
+        let config = Config::load(&self.path)?;
+
+        let current = self.get();
+        if config.port != current.port {
+            log::warn!(
+                "Changing the port needs a restart, still listening on {}",
+                current.port
+            );
+        }
+        if config.jobs.max_jobs != current.jobs.max_jobs {
+            log::warn!("Changing jobs.max_jobs needs a restart");
+        }
+
+        *self.current.write().unwrap() = Arc::new(config);
+        log::info!("Reloaded {}", self.path.display());
+
+        Ok(())
+    }
+
+    fn is_modified(&self) -> bool {
+        modified(&self.path) != *self.modified.read().unwrap()
+    }
+
+    /// Reloads on SIGHUP and, with `reload_interval_secs` set, when the file changes,
+    /// until draining starts.
+    pub async fn watch(self: Arc<Self>, shutdown: Arc<Shutdown>) {
+        let mut hangup = hangup();
+
+        loop {
+            let interval = self.get().reload_interval_secs;
+            let poll = async {
+                if interval == 0 {
+                    std::future::pending::<()>().await;
+                }
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+            };
+
+            tokio::select! {
+                _ = shutdown.wait() => return,
+                _ = hangup.recv() => {}
+                _ = poll => {
+                    if !self.is_modified() {
+                        continue;
+                    }
+                }
+            }
+
+            if let Err(e) = self.reload() {
+                log::error!("Could not reload config, keeping the current one: {:#}", e);
+            }
+        }
+    }
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(unix)]
+fn hangup() -> tokio::signal::unix::Signal {
+    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("SIGHUP handler can be installed")
+}
+
+#[cfg(not(unix))]
+fn hangup() -> NoHangup {
+    NoHangup
+}
+
+/// Stands in for SIGHUP where there is none.
+#[cfg(not(unix))]
+struct NoHangup;
+
+#[cfg(not(unix))]
+impl NoHangup {
+    async fn recv(&mut self) -> Option<()> {
+        std::future::pending().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("gimme-3d-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("config.toml");
+
+        let config = std::fs::read_to_string("config.test.toml")?;
+        std::fs::write(&path, &config)?;
+        let shared = Shared::load_arc(&path)?;
+        assert_eq!(shared.get().limits.max_width, 2048);
+
+        std::fs::write(
+            &path,
+            config.replace("max_width = 2048", "max_width = 1024"),
+        )?;
+        shared.reload()?;
+        assert_eq!(shared.get().limits.max_width, 1024);
+
+        // a broken file keeps the last good config
+        std::fs::write(&path, "port = ")?;
+        assert!(shared.reload().is_err());
+        assert_eq!(shared.get().limits.max_width, 1024);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
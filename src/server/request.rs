@@ -1,33 +1,105 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::fmt::Formatter;
 
+use anyhow::Context;
 use bytes::BufMut;
 use futures_util::TryStreamExt;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use warp::multipart::FormData;
 
+use crate::antialias::Filter;
+use crate::background::{Background, DropShadow, Layer};
+use crate::color::Color;
+use crate::composite::BlendMode;
+use crate::gltf::{SceneSelector, Visibility};
+use crate::img;
+use crate::output::Format;
+use crate::render;
+use crate::server::config::Config;
+use crate::shadow::ContactShadow;
+
 #[derive(Deserialize, Serialize, Default)]
 pub struct Request {
+    /// Id of a product in the local model directory, it stands in for `model_url`
+    /// and the product's manifest fills in the mask and supersample factor.
+    pub product: Option<String>,
     pub model_url: Option<String>,
     pub model: Option<Vec<u8>>,
     // todo these should just be vecs
     pub texture_urls: Option<Vec<String>>,
     pub textures: Option<Vec<Vec<u8>>>,
+    /// Output size, taken from the mask when there is one.
+    #[serde(default)]
     pub width: u32,
+    #[serde(default)]
     pub height: u32,
+    /// Output format, negotiated from the `Accept` header when not set.
+    pub format: Option<Format>,
+    /// Quality (1-100) of lossy output formats.
+    pub quality: Option<u8>,
+    /// Quality (1-100) of the alpha channel of lossy webp.
+    pub alpha_quality: Option<u8>,
+    /// Lossless webp, by default lossless unless `quality` is set.
+    pub lossless: Option<bool>,
+    /// Render this many times larger before downsampling, capped by the config.
+    pub supersample: Option<u32>,
+    /// Downsampling filter.
+    pub filter: Option<Filter>,
+    /// Composited under the render, transparent by default.
+    #[serde(default)]
+    pub background: Background,
+    /// Drop shadow of the render on the background.
+    pub shadow: Option<DropShadow>,
+    /// Soft shadow on the ground beneath the model.
+    pub contact_shadow: Option<ContactShadow>,
+    /// Scene by index or name, the default scene or else the first one when not set.
+    pub scene: Option<SceneSelector>,
+    /// Nodes or meshes to render by name or glob, all by default.
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// Colors (`#rrggbb`, `#rrggbbaa` or `[r, g, b, a]`) of mesh nodes by name,
+    /// these are rendered without a texture.
+    #[serde(default)]
+    pub colors: BTreeMap<String, Color>,
+    /// Image (e.g. a product photo) the render is blended onto, it decides the output size.
+    pub mask_url: Option<String>,
+    pub mask: Option<Vec<u8>>,
+    /// How the render is blended onto the mask.
+    pub blend: Option<BlendMode>,
+    /// The background at the output size, loaded before the request is queued,
+    /// so the render loop never waits for its download.
+    #[serde(skip)]
+    pub layer: Option<Layer>,
 }
 
 impl fmt::Debug for Request {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("Request")
+            .field("product", &self.product)
             .field("model", &self.model.is_some())
             .field("model_url", &self.model_url)
             .field("textures (length)", &self.textures.is_some())
             .field("texture_urls (length)", &self.texture_urls.is_some())
             .field("width", &self.width)
             .field("height", &self.height)
+            .field("format", &self.format)
+            .field("quality", &self.quality)
+            .field("alpha_quality", &self.alpha_quality)
+            .field("lossless", &self.lossless)
+            .field("supersample", &self.supersample)
+            .field("filter", &self.filter)
+            .field("background", &self.background)
+            .field("shadow", &self.shadow)
+            .field("contact_shadow", &self.contact_shadow)
+            .field("scene", &self.scene)
+            .field("visibility", &self.visibility)
+            .field("colors", &self.colors)
+            .field("mask", &self.mask.is_some())
+            .field("mask_url", &self.mask_url)
+            .field("blend", &self.blend)
+            .field("layer", &self.layer.is_some())
             .finish()
     }
 }
@@ -37,6 +109,57 @@ impl Request {
         self.textures.is_some()
     }
 
+    pub fn has_mask(&self) -> bool {
+        self.mask.is_some() || self.mask_url.is_some()
+    }
+
+    /// Loads the background, the output size has to be final (a mask sets it).
+    pub async fn load_layer(&mut self, config: &Config) -> anyhow::Result<()> {
+        let layer = Layer::load(
+            &self.background,
+            self.shadow,
+            self.width,
+            self.height,
+            &config.limits,
+        )
+        .await
+        .context("loading background")?;
+        self.layer = Some(layer);
+        Ok(())
+    }
+
+    /// Points the request at the model of its product, if it has one,
+    /// and fills in what it leaves out from the product's manifest.
+    /// A `model_url` of the request itself is checked with `check_model_url`,
+    /// its image urls with `img::check_request_url` before the manifest's are filled in.
+    pub fn resolve_product(&mut self, config: &Config) -> anyhow::Result<()> {
+        let texture_urls = self.texture_urls.iter().flatten();
+        check_image_urls(texture_urls.chain(&self.mask_url), &self.background)?;
+
+        let Some(id) = &self.product else {
+            return check_model_url(self.model_url.as_deref(), config);
+        };
+
+        let (model_url, manifest) = config.models.product(id)?;
+        self.model_url = Some(model_url);
+        if !self.has_mask() {
+            self.mask_url = manifest.mask.as_deref().map(|mask| manifest.resolve(mask));
+        }
+        self.supersample = self.supersample.or(manifest.supersample);
+
+        Ok(())
+    }
+
+    pub fn render_options(&self, config: &Config) -> render::Options {
+        render::Options {
+            antialias: config.antialias(self.supersample, self.filter),
+            contact_shadow: self.contact_shadow,
+            scene: self.scene.clone(),
+            visibility: self.visibility.clone(),
+            colors: self.colors.clone(),
+        }
+    }
+
     pub async fn from_form_data(form: FormData) -> anyhow::Result<Self> {
         let fields: HashMap<String, Vec<u8>> = form
             .and_then(|mut field| async move {
@@ -65,24 +188,33 @@ impl Request {
             }
         }
 
-        if model_url.is_none() && model.is_none() {
-            return Err(anyhow::anyhow!("model_url or model field is required"));
+        let product = optional_field(&fields, "product")?;
+
+        if model_url.is_none() && model.is_none() && product.is_none() {
+            return Err(anyhow::anyhow!(
+                "model_url, model or product field is required"
+            ));
         }
 
-        let width = String::from_utf8(
-            fields
-                .get("width")
-                .ok_or(ClientError::MissingField("width".to_string()))?
-                .to_vec(),
-        )?
-        .parse()?;
-        let height = String::from_utf8(
-            fields
-                .get("height")
-                .ok_or(ClientError::MissingField("height".to_string()))?
-                .to_vec(),
-        )?
-        .parse()?;
+        let mask = fields
+            .get("mask")
+            .filter(|mask| !mask.is_empty())
+            .map(|mask| mask.to_vec());
+        let mask_url = optional_field(&fields, "mask_url")?;
+        let blend = optional_field(&fields, "blend")?
+            .map(|blend| blend.parse())
+            .transpose()?;
+
+        // the size of a mask wins over the requested one
+        let size_field = |name: &str| -> anyhow::Result<u32> {
+            match optional_field(&fields, name)? {
+                Some(value) => Ok(value.parse()?),
+                None if mask.is_some() || mask_url.is_some() => Ok(0),
+                None => Err(ClientError::MissingField(name.to_string()).into()),
+            }
+        };
+        let width = size_field("width")?;
+        let height = size_field("height")?;
 
         let mut textures = Vec::new();
         fields
@@ -90,17 +222,183 @@ impl Request {
             .filter(|(k, _)| k.starts_with("texture"))
             .for_each(|(_, v)| textures.push(v.to_vec()));
 
+        let format = optional_field(&fields, "format")?
+            .map(|format| format.parse())
+            .transpose()?;
+        let quality = optional_field(&fields, "quality")?
+            .map(|quality| quality.parse())
+            .transpose()?;
+        let alpha_quality = optional_field(&fields, "alpha_quality")?
+            .map(|alpha_quality| alpha_quality.parse())
+            .transpose()?;
+        let lossless = optional_field(&fields, "lossless")?
+            .map(|lossless| lossless.parse())
+            .transpose()?;
+        let supersample = optional_field(&fields, "supersample")?
+            .map(|supersample| supersample.parse())
+            .transpose()?;
+        let filter = optional_field(&fields, "filter")?
+            .map(|filter| filter.parse())
+            .transpose()?;
+        let background = optional_field(&fields, "background")?
+            .map(|background| background.parse())
+            .transpose()?
+            .unwrap_or_default();
+        let shadow = optional_field(&fields, "shadow")?
+            .map(|shadow| serde_json::from_str(&shadow))
+            .transpose()?;
+        let contact_shadow = optional_field(&fields, "contact_shadow")?
+            .map(|contact_shadow| serde_json::from_str(&contact_shadow))
+            .transpose()?;
+        let scene = optional_field(&fields, "scene")?.and_then(|scene| scene.parse().ok());
+        let visibility = optional_field(&fields, "visibility")?
+            .map(|visibility| serde_json::from_str(&visibility))
+            .transpose()?
+            .unwrap_or_default();
+        let colors = optional_field(&fields, "colors")?
+            .map(|colors| serde_json::from_str(&colors))
+            .transpose()?
+            .unwrap_or_default();
+
         Ok(Request {
+            product,
             model,
             model_url,
             texture_urls: None,
-            textures: Some(textures),
+            // without textures the product's default textures are rendered, like for `/render`
+            textures: (!textures.is_empty()).then_some(textures),
             width,
             height,
+            format,
+            quality,
+            alpha_quality,
+            lossless,
+            supersample,
+            filter,
+            background,
+            shadow,
+            contact_shadow,
+            scene,
+            visibility,
+            colors,
+            mask_url,
+            mask,
+            blend,
+            layer: None,
         })
     }
 }
 
+/// Rejects model locations of a request the server must not read on its behalf, see `[storage]`.
+fn check_model_url(model_url: Option<&str>, config: &Config) -> anyhow::Result<()> {
+    if let Some(model_url) = model_url {
+        config
+            .storage
+            .check_request_location(model_url, &config.models.models_base_url)?;
+    }
+    Ok(())
+}
+
+/// Rejects texture, mask and background locations of a request that are not `http(s)://` urls.
+fn check_image_urls<'a>(
+    urls: impl Iterator<Item = &'a String>,
+    background: &'a Background,
+) -> Result<(), crate::error::Error> {
+    let background = match background {
+        Background::Image(url) => Some(url),
+        _ => None,
+    };
+    urls.chain(background)
+        .try_for_each(|url| img::check_request_url(url))
+}
+
+fn optional_field(fields: &HashMap<String, Vec<u8>>, name: &str) -> anyhow::Result<Option<String>> {
+    match fields.get(name) {
+        Some(value) if !value.is_empty() => Ok(Some(String::from_utf8(value.to_vec())?)),
+        _ => Ok(None),
+    }
+}
+
+/// Renders one model once per texture set, the sets are applied like `texture_urls` of `Request`.
+#[derive(Deserialize, Serialize, Default)]
+pub struct BatchRequest {
+    /// Id of a product, like for `Request`.
+    pub product: Option<String>,
+    pub model_url: Option<String>,
+    pub model: Option<Vec<u8>>,
+    pub texture_sets: Vec<Vec<String>>,
+    pub width: u32,
+    pub height: u32,
+    pub format: Option<Format>,
+    pub quality: Option<u8>,
+    pub alpha_quality: Option<u8>,
+    pub lossless: Option<bool>,
+    pub supersample: Option<u32>,
+    pub filter: Option<Filter>,
+    #[serde(default)]
+    pub background: Background,
+    pub shadow: Option<DropShadow>,
+    pub contact_shadow: Option<ContactShadow>,
+    pub scene: Option<SceneSelector>,
+    #[serde(default)]
+    pub visibility: Visibility,
+    #[serde(default)]
+    pub colors: BTreeMap<String, Color>,
+}
+
+impl BatchRequest {
+    /// Points the request at the model of its product, like for `Request`.
+    /// Batches are not blended onto a mask, the manifest's mask is not used.
+    pub fn resolve_product(&mut self, config: &Config) -> anyhow::Result<()> {
+        check_image_urls(self.texture_sets.iter().flatten(), &self.background)?;
+
+        let Some(id) = &self.product else {
+            return check_model_url(self.model_url.as_deref(), config);
+        };
+
+        let (model_url, manifest) = config.models.product(id)?;
+        self.model_url = Some(model_url);
+        self.supersample = self.supersample.or(manifest.supersample);
+
+        Ok(())
+    }
+
+    pub fn render_options(&self, config: &Config) -> render::Options {
+        render::Options {
+            antialias: config.antialias(self.supersample, self.filter),
+            contact_shadow: self.contact_shadow,
+            scene: self.scene.clone(),
+            visibility: self.visibility.clone(),
+            colors: self.colors.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for BatchRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BatchRequest")
+            .field("product", &self.product)
+            .field("model", &self.model.is_some())
+            .field("model_url", &self.model_url)
+            .field("texture_sets (length)", &self.texture_sets.len())
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("format", &self.format)
+            .field("quality", &self.quality)
+            .field("alpha_quality", &self.alpha_quality)
+            .field("lossless", &self.lossless)
+            .field("supersample", &self.supersample)
+            .field("filter", &self.filter)
+            .field("background", &self.background)
+            .field("shadow", &self.shadow)
+            .field("contact_shadow", &self.contact_shadow)
+            .field("scene", &self.scene)
+            .field("visibility", &self.visibility)
+            .field("colors", &self.colors)
+            .finish()
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum ClientError {
     #[error("Error while parsing form data: {0}")]
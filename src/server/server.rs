@@ -1,81 +1,239 @@
 use std::fmt::{Debug, Formatter};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 use image::DynamicImage;
 use three_d::HeadlessContext;
 use tokio::sync::{mpsc, oneshot, Semaphore};
+use warp::http::StatusCode;
 use warp::multipart::FormData;
 use warp::reply::Response;
-use warp::Filter;
-
+use warp::{Filter, Reply};
+
+use crate::composite::{self, BlendMode};
+use crate::error::Error;
+use crate::img;
+use crate::limits::LimitError;
+use crate::mockup::{MapCache, Mockup};
+use crate::output::{self, Format};
 use crate::render::*;
 
-use super::{config, debug, logger, request};
+use super::cache::{self, Cache};
+use super::metrics::Metrics;
+use super::shutdown::{self, Shutdown};
+use super::{
+    accept, batch, config, debug, health, jobs, logger, models, prefetch, reload, request,
+};
 
 pub(crate) type ResultChannel = oneshot::Sender<Result<DynamicImage>>;
 
-pub async fn run() {
+/// Runs the server until it is terminated, fails on a malformed config.
+pub async fn run(config_path: &str) -> Result<()> {
     logger::init();
 
-    let config = config::Config::parse_toml("config.toml".to_string()).unwrap_or_default();
-    let local_model_dir = config.models.local_model_dir;
+    let shared = reload::Shared::load_arc(config_path)?;
+    let config = shared.get();
 
     let context = HeadlessContext::new().unwrap();
 
     let (request_tx, mut request_rx) = mpsc::channel::<(request::Request, ResultChannel)>(10);
+    let (batch_tx, mut batch_rx) =
+        mpsc::channel::<(request::BatchRequest, batch::BatchChannel)>(10);
+
+    let shutdown = Shutdown::new_arc();
+    let health_state = health::State::new_arc();
+
+    let prefetch_state = config.prefetch.enabled.then(|| {
+        let state =
+            prefetch::State::new_arc(config.models.models.len(), config.prefetch.ready_ratio);
+        tokio::spawn(prefetch::run(
+            state.clone(),
+            config.models.clone(),
+            config.storage.clone(),
+            config.prefetch.concurrency,
+        ));
+        state
+    });
+
+    let mut server = tokio::spawn(serve(
+        shared.clone(),
+        request_tx,
+        batch_tx,
+        shutdown.clone(),
+        health_state.clone(),
+        prefetch_state,
+    ));
+
+    tokio::spawn(shared.clone().watch(shutdown.clone()));
 
     tokio::spawn(async move {
-        serve(config.port, request_tx).await;
+        shutdown::signal().await;
+        log::info!("Termination signal received, draining");
+        shutdown.start();
     });
 
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(1));
+    let canary_interval = Duration::from_secs(config.health.canary_interval_secs);
+    let mut last_canary: Option<std::time::Instant> = None;
+
     loop {
-        let (request, response_tx) = request_rx.recv().await.unwrap();
-        if request.has_raw_textures() {
-            let pixels = render_raw_images(
+        // the server future resolves once draining is done (or timed out),
+        // dropping every sender, so queued renders are finished before exiting
+        let (mut request, response_tx) = tokio::select! {
+            received = request_rx.recv() => match received {
+                Some(received) => received,
+                None => break,
+            },
+            _ = &mut server => break,
+            Some((batch_request, images_tx)) = batch_rx.recv() => {
+                health_state.beat();
+                let config = shared.get();
+                batch::run(
+                    batch_request,
+                    images_tx,
+                    &context,
+                    &config,
+                    &health_state,
+                )
+                .await;
+                continue;
+            }
+            _ = heartbeat.tick() => {
+                health_state.beat();
+
+                if shared.get().health.canary
+                    && !matches!(last_canary, Some(at) if at.elapsed() < canary_interval)
+                {
+                    let result = canary(&context);
+                    if let Err(e) = &result {
+                        log::error!("Canary render failed: {}", e);
+                    }
+                    health_state.set_canary(&result);
+                    last_canary = Some(std::time::Instant::now());
+                }
+                continue;
+            }
+        };
+
+        health_state.beat();
+
+        // the config as of receiving the request, a reload while it waited in the queue applies
+        let config = shared.get();
+        let local_model_dir = &config.models.local_model_dir;
+        let render_options = request.render_options(&config);
+
+        let Some(layer) = request.layer.take() else {
+            let _ = response_tx.send(Err(anyhow::anyhow!("background was not loaded")));
+            continue;
+        };
+
+        let pixels = if request.has_raw_textures() {
+            render_raw_images(
                 request.model_url,
                 request.model,
                 request.textures.unwrap(),
                 &context,
-                request.width * config.upscale_factor,
-                request.height * config.upscale_factor,
-                &local_model_dir,
+                request.width,
+                request.height,
+                &render_options,
+                &config.limits,
+                local_model_dir,
+                &config.storage,
             )
-            .await;
-            let _ = response_tx.send(pixels);
-            continue;
-        }
+            .await
+        } else {
+            render_urls(
+                request.model_url,
+                request.model,
+                request.texture_urls.unwrap_or_default(),
+                &context,
+                request.width,
+                request.height,
+                &render_options,
+                &config.limits,
+                local_model_dir,
+                &config.storage,
+            )
+            .await
+        };
 
-        let pixels = render_urls(
-            request.model_url,
-            request.model,
-            request.texture_urls.unwrap_or_default(),
-            &context,
-            request.width * config.upscale_factor,
-            request.height * config.upscale_factor,
-            &local_model_dir,
-        )
-        .await;
-        let _ = response_tx.send(pixels);
+        let _ = response_tx.send(pixels.map(|pixels| layer.composite(pixels)));
     }
+
+    log::info!("Shutdown complete");
+
+    Ok(())
 }
 
-async fn serve(port: u16, request_tx: mpsc::Sender<(request::Request, ResultChannel)>) {
+async fn serve(
+    shared: Arc<reload::Shared>,
+    request_tx: mpsc::Sender<(request::Request, ResultChannel)>,
+    batch_tx: mpsc::Sender<(request::BatchRequest, batch::BatchChannel)>,
+    shutdown: Arc<Shutdown>,
+    health_state: Arc<health::State>,
+    prefetch_state: Option<Arc<prefetch::State>>,
+) {
+    // settings that need a restart to change
+    let config = shared.get();
+
+    let metrics = Metrics::new_arc();
+    let cache = Cache::new_arc(metrics.clone());
+    let cache_form = cache.clone();
+    let cache_render = cache.clone();
+    let maps = MapCache::new_arc();
+    let maps_form = maps.clone();
+    let maps_render = maps.clone();
+
     let semaphore = Arc::new(Semaphore::new(1));
     let semaphore_clone = semaphore.clone();
     let request_tx_clone = request_tx.clone();
     let request_tx_debug = request_tx.clone();
+    let job_store = jobs::Store::new_arc(config.jobs.max_jobs);
+    let jobs = jobs::routes(
+        job_store.clone(),
+        semaphore.clone(),
+        request_tx.clone(),
+        shutdown.clone(),
+        shared.clone(),
+        maps,
+    );
+    let render_batch = batch::post(
+        semaphore.clone(),
+        batch_tx,
+        shutdown.clone(),
+        shared.clone(),
+    );
+    let shutdown_form = shutdown.clone();
+    let shutdown_render = shutdown.clone();
+    let shutdown_health = shutdown.clone();
+    let config_form = shared.clone();
+    let config_render = shared.clone();
     let render_form = warp::post()
         .and(warp::path("render-form"))
         .and(warp::multipart::form().max_length(Some(1024 * 1024 * 1024)))
         .and(warp::header::optional("accept"))
+        .and(warp::header::optional("if-none-match"))
         .and(warp::any().map(move || semaphore_clone.clone()))
         .and(warp::any().map(move || request_tx_clone.clone()))
+        .and(warp::any().map(move || shutdown_form.clone()))
+        .and(warp::any().map(move || cache_form.clone()))
+        .and(warp::any().map(move || maps_form.clone()))
+        .and(warp::any().map(move || config_form.get()))
         .and_then(
             |form: FormData,
              accept_header: Option<String>,
+             if_none_match: Option<String>,
              sem: Arc<Semaphore>,
-             request_tx: mpsc::Sender<(request::Request, ResultChannel)>| async move {
+             request_tx: mpsc::Sender<(request::Request, ResultChannel)>,
+             shutdown: Arc<Shutdown>,
+             cache: Arc<Cache>,
+             maps: Arc<MapCache>,
+             config: Arc<config::Config>| async move {
+                if shutdown.is_draining() {
+                    return Ok(unavailable());
+                }
+
                 let start = std::time::Instant::now();
 
                 let request_future = request::Request::from_form_data(form).await;
@@ -85,28 +243,18 @@ async fn serve(port: u16, request_tx: mpsc::Sender<(request::Request, ResultChan
                 }
 
                 let r = request_future.unwrap();
-                let permit = sem.acquire_owned().await.unwrap();
-
-                let (response_tx, response_rx) = oneshot::channel();
-
-                let width = r.width;
-                let height = r.height;
-
-                request_tx.try_send((r, response_tx)).unwrap();
-                let pixels = match response_rx.await.unwrap() {
-                    Ok(content) => content,
-                    Err(e) => {
-                        drop(permit);
-                        log::error!("Error: {}", e);
-                        return Err(warp::reject::Rejection::from(InternalServerError(e)));
-                    }
-                };
-
-                drop(permit);
 
-                log::info!("Time overall: {:?}", start.elapsed());
-
-                respond(accept_header, pixels, start, width, height)
+                let headers = (accept_header, if_none_match);
+                handle(
+                    r,
+                    headers,
+                    &sem,
+                    &request_tx,
+                    (&cache, &maps),
+                    &config,
+                    start,
+                )
+                .await
             },
         );
 
@@ -114,89 +262,346 @@ async fn serve(port: u16, request_tx: mpsc::Sender<(request::Request, ResultChan
         .and(warp::path("render"))
         .and(warp::body::json())
         .and(warp::header::optional("accept"))
+        .and(warp::header::optional("if-none-match"))
         .and(warp::any().map(move || semaphore.clone()))
         .and(warp::any().map(move || request_tx.clone()))
+        .and(warp::any().map(move || shutdown_render.clone()))
+        .and(warp::any().map(move || cache_render.clone()))
+        .and(warp::any().map(move || maps_render.clone()))
+        .and(warp::any().map(move || config_render.get()))
         .and_then(
             move |r: request::Request,
                   accept_header: Option<String>,
+                  if_none_match: Option<String>,
                   sem: Arc<Semaphore>,
-                  request_tx: mpsc::Sender<(request::Request, ResultChannel)>| {
+                  request_tx: mpsc::Sender<(request::Request, ResultChannel)>,
+                  shutdown: Arc<Shutdown>,
+                  cache: Arc<Cache>,
+                  maps: Arc<MapCache>,
+                  config: Arc<config::Config>| {
                 async move {
-                    let start = std::time::Instant::now();
-                    let permit = sem.acquire_owned().await.unwrap();
-
-                    let (response_tx, response_rx) = oneshot::channel();
-
-                    let width = r.width;
-                    let height = r.height;
-
-                    request_tx.try_send((r, response_tx)).unwrap();
-                    let pixels = response_rx.await.unwrap().unwrap();
+                    if shutdown.is_draining() {
+                        return Ok(unavailable());
+                    }
 
-                    drop(permit);
+                    let start = std::time::Instant::now();
 
-                    respond(accept_header, pixels, start, width, height)
+                    let headers = (accept_header, if_none_match);
+                    handle(
+                        r,
+                        headers,
+                        &sem,
+                        &request_tx,
+                        (&cache, &maps),
+                        &config,
+                        start,
+                    )
+                    .await
                 }
             },
         );
 
-    let health = warp::get().and(warp::path("health")).map(|| "ok");
+    let health = warp::get().and(warp::path("health")).map(move || {
+        if shutdown_health.is_draining() {
+            return unavailable();
+        }
+        "ok".into_response()
+    });
+
+    let livez = health::livez(health_state.clone(), config.health.clone());
+    let readyz = health::readyz(
+        health_state,
+        shutdown.clone(),
+        config.health.clone(),
+        shared.clone(),
+        prefetch_state,
+    );
 
     let routes = render
         .or(health)
+        .or(livez)
+        .or(readyz)
+        .or(jobs)
+        .or(render_batch)
         .or(render_form)
+        .or(models::routes(shared.clone()))
         .or(debug::get())
-        .or(debug::post(request_tx_debug));
+        .or(debug::post(
+            request_tx_debug,
+            shared.clone(),
+            shutdown.clone(),
+        ))
+        .or(Metrics::route(metrics.clone()))
+        .with(warp::log::custom(move |info| metrics.track(info)));
+
+    let signal_shutdown = shutdown.clone();
+    let (_, server) = warp::serve(routes)
+        .bind_with_graceful_shutdown(([0, 0, 0, 0], config.port), async move {
+            signal_shutdown.wait().await
+        });
+    tokio::pin!(server);
+
+    tokio::select! {
+        _ = &mut server => return,
+        _ = shutdown.wait() => {}
+    }
+
+    // accepted jobs are not bound to a connection, wait for them separately
+    let drained = async move {
+        server.await;
+        job_store.drained().await;
+    };
 
-    warp::serve(routes).run(([0, 0, 0, 0], port)).await;
+    let drain_timeout = Duration::from_secs(config.drain_timeout_secs);
+    if tokio::time::timeout(drain_timeout, drained).await.is_err() {
+        log::warn!(
+            "Drain timeout of {:?} elapsed, dropping in-flight requests",
+            drain_timeout
+        );
+    }
 }
 
-fn respond(
-    accept_header: Option<String>,
-    pixels: DynamicImage,
+/// Renders a request of `/render` or `/render-form`, blended onto its mask if it has one.
+/// `headers` are the `Accept` and `If-None-Match` headers, a cached result is served without a render.
+async fn handle(
+    mut r: request::Request,
+    headers: (Option<String>, Option<String>),
+    sem: &Semaphore,
+    request_tx: &mpsc::Sender<(request::Request, ResultChannel)>,
+    (cache, maps): (&Cache, &MapCache),
+    config: &config::Config,
     start: std::time::Instant,
-    width: u32,
-    height: u32,
 ) -> Result<Response, warp::Rejection> {
-    let result = if pixels.width() == width && pixels.height() == height {
-        pixels
+    let (accept_header, if_none_match) = headers;
+    let format = r
+        .format
+        .unwrap_or_else(|| accept::negotiate(accept_header.as_deref()));
+    let options = config
+        .output
+        .options(format, r.quality, r.alpha_quality, r.lossless);
+
+    if let Err(e) = r.resolve_product(config) {
+        return failed(e);
+    }
+
+    // the size of a mask is only known once it is loaded, it is checked again then
+    if !r.has_mask() {
+        if let Err(e) = config.check_limits(r.width, r.height, r.supersample) {
+            return Ok(rejected(&e));
+        }
+    }
+
+    let key = cache.key(&r, format, options, config);
+    if let Some(key) = &key {
+        if cache::matches(if_none_match.as_deref(), key) {
+            cache.not_modified();
+            return Ok(warp::http::response::Builder::new()
+                .status(StatusCode::NOT_MODIFIED)
+                .header("ETag", cache::etag(key))
+                .header("Vary", "Accept")
+                .body(Default::default())
+                .unwrap());
+        }
+        if let Some(body) = cache.get(key, &config.cache) {
+            log::info!("Cache hit, time overall: {:?}", start.elapsed());
+            return Ok(image_response(format, body.to_vec(), Some(key)));
+        }
+    }
+
+    let mask = match load_mask(&mut r, config, maps).await {
+        Ok(mask) => mask,
+        Err(e) => return failed(e),
+    };
+
+    if let Err(e) = config.check_limits(r.width, r.height, r.supersample) {
+        return Ok(rejected(&e));
+    }
+
+    if let Err(e) = r.load_layer(config).await {
+        return failed(e);
+    }
+
+    let pixels = match submit(r, sem, request_tx).await {
+        Ok(content) => content,
+        Err(e) => return failed(e),
+    };
+
+    let pixels = match mask {
+        Some(mask) => mask.apply(pixels),
+        None => pixels,
+    };
+
+    let body = encode(format, options, pixels)
+        .map_err(|e| warp::reject::Rejection::from(InternalServerError(e)))?;
+    if let Some(key) = &key {
+        cache.insert(key, Arc::new(body.clone()), &config.cache);
+    }
+
+    log::info!("Time overall: {:?}", start.elapsed());
+
+    Ok(image_response(format, body, key.as_deref()))
+}
+
+/// The mask of a request and what the render goes through before it is blended onto it.
+pub(crate) struct Mask {
+    image: DynamicImage,
+    mockup: Mockup,
+    blend: BlendMode,
+}
+
+impl Mask {
+    /// Warps and shades the render with the mockup maps of the model and blends it onto the mask.
+    pub(crate) fn apply(&self, pixels: DynamicImage) -> DynamicImage {
+        composite::blend(&self.image, &self.mockup.apply(pixels), self.blend)
+    }
+}
+
+/// Loads the mask of the request, if it has one, and sets the output size to the size
+/// of the mask scaled down to fit into the limits, supersampling included. The mask is scaled to that size.
+pub(crate) async fn load_mask(
+    request: &mut request::Request,
+    config: &config::Config,
+    maps: &MapCache,
+) -> Result<Option<Mask>> {
+    let limits = &config.limits;
+    let bytes = match (request.mask.take(), &request.mask_url) {
+        (Some(bytes), _) => bytes,
+        (None, Some(url)) => img::download(url.clone()).await?,
+        (None, None) => return Ok(None),
+    };
+
+    // a mask over the limits is rejected like one, any other decode failure is the client's
+    let image = img::decode(&bytes, limits).map_err(|e| match e.downcast::<LimitError>() {
+        Ok(limit_error) => limit_error.into(),
+        Err(e) => anyhow::Error::from(Error::InvalidMask(e.to_string())),
+    })?;
+    let (width, height) = composite::fit(
+        image.width(),
+        image.height(),
+        limits.max_width,
+        limits.max_height,
+    );
+    // the render is supersampled, so it has to fit into the render pixels with the factor
+    let factor = config.antialias(request.supersample, None).supersample as u64;
+    let (width, height) =
+        composite::fit_pixels(width, height, limits.max_render_pixels / (factor * factor));
+    request.width = width;
+    request.height = height;
+
+    let image = if (width, height) == (image.width(), image.height()) {
+        image
     } else {
-        image::imageops::thumbnail(&pixels, width, height).into()
+        image.thumbnail_exact(width, height)
+    };
+
+    let model_url = request.model_url.as_deref().unwrap_or_default();
+    let mockup = match config.models.mockup_maps(model_url)? {
+        Some((mockup, dir)) => Mockup::load(&mockup, &dir, width, height, limits, maps).await?,
+        None => Mockup::default(),
     };
 
-    if let Some(mime) = accept_header {
-        if mime.contains("image/webp") {
-            let start = std::time::Instant::now();
-            let mut writer = std::io::Cursor::new(Vec::new());
-            result
-                .write_to(&mut writer, image::ImageOutputFormat::WebP)
-                .unwrap();
-
-            log::info!("Time webp: {:?}", start.elapsed());
-            log::info!("Time overall: {:?}", start.elapsed());
-
-            return Ok::<Response, warp::Rejection>(
-                warp::http::response::Builder::new()
-                    .header("Content-Type", "image/webp")
-                    .body(writer.into_inner().into())
-                    .unwrap(),
-            );
+    Ok(Some(Mask {
+        image,
+        mockup,
+        blend: request.blend.unwrap_or_default(),
+    }))
+}
+
+pub(crate) fn unavailable() -> Response {
+    warp::reply::with_status("shutting down", StatusCode::SERVICE_UNAVAILABLE).into_response()
+}
+
+/// Response for a request exceeding the configured limits.
+pub(crate) fn rejected(e: &LimitError) -> Response {
+    let status = match e {
+        LimitError::InvalidSize { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => StatusCode::PAYLOAD_TOO_LARGE,
+    };
+
+    warp::reply::with_status(e.to_string(), status).into_response()
+}
+
+/// Turns a failed render into a response, a texture over the limits, a forbidden model or image location,
+/// an unknown product, scene or node is the client's fault.
+pub(crate) fn failed(e: anyhow::Error) -> Result<Response, warp::Rejection> {
+    log::error!("Error: {}", e);
+
+    if let Some(limit_error) = e.downcast_ref::<LimitError>() {
+        return Ok(rejected(limit_error));
+    }
+
+    match e.downcast_ref::<Error>() {
+        Some(Error::UnknownProduct(_)) => {
+            Ok(warp::reply::with_status(e.to_string(), StatusCode::NOT_FOUND).into_response())
         }
+        Some(Error::ForbiddenLocation(_) | Error::ForbiddenImageLocation(_)) => {
+            Ok(warp::reply::with_status(e.to_string(), StatusCode::FORBIDDEN).into_response())
+        }
+        Some(
+            Error::SceneNotFound { .. }
+            | Error::NodeNotFound(_)
+            | Error::InvalidNodePattern { .. }
+            | Error::InvalidMask(_),
+        ) => Ok(
+            warp::reply::with_status(e.to_string(), StatusCode::UNPROCESSABLE_ENTITY)
+                .into_response(),
+        ),
+        _ => Err(warp::reject::Rejection::from(InternalServerError(e))),
     }
+}
 
-    let mut writer = std::io::Cursor::new(Vec::new());
-    result
-        .write_to(&mut writer, image::ImageOutputFormat::Png)
-        .unwrap();
+/// An encoded image, with the ETag of its cache key when it is cacheable.
+fn image_response(format: Format, body: Vec<u8>, key: Option<&str>) -> Response {
+    let mut response = warp::http::response::Builder::new()
+        .header("Content-Type", format.content_type())
+        .header("Vary", "Accept");
+    if let Some(key) = key {
+        response = response.header("ETag", cache::etag(key));
+    }
 
-    log::info!("Time overall: {:?}", start.elapsed());
+    response.body(body.into()).unwrap()
+}
+
+/// Encodes the rendered pixels, they are already downsampled to the requested size by the render.
+pub(crate) fn encode(
+    format: Format,
+    options: output::Options,
+    pixels: DynamicImage,
+) -> Result<Vec<u8>> {
+    let start = std::time::Instant::now();
 
-    Ok::<Response, warp::Rejection>(
-        warp::http::response::Builder::new()
-            .header("Content-Type", "image/png")
-            .body(writer.into_inner().into())
-            .unwrap(),
-    )
+    let body = output::encode(&pixels, format, &options)?;
+
+    log::info!(
+        "Time encode ({}): {:?}",
+        format.content_type(),
+        start.elapsed()
+    );
+
+    Ok(body)
+}
+
+/// Waits for the render slot and hands the request over to the render loop.
+pub(crate) async fn submit(
+    request: request::Request,
+    sem: &Semaphore,
+    request_tx: &mpsc::Sender<(request::Request, ResultChannel)>,
+) -> Result<DynamicImage> {
+    let _permit = sem.acquire().await?;
+    dispatch(request, request_tx).await
+}
+
+/// Hands the request over to the render loop, the caller must hold the render slot.
+pub(crate) async fn dispatch(
+    request: request::Request,
+    request_tx: &mpsc::Sender<(request::Request, ResultChannel)>,
+) -> Result<DynamicImage> {
+    let (response_tx, response_rx) = oneshot::channel();
+    request_tx
+        .try_send((request, response_tx))
+        .map_err(|e| anyhow::anyhow!("could not queue render: {}", e))?;
+
+    response_rx.await?
 }
 
 struct InternalServerError(anyhow::Error);
@@ -210,3 +615,62 @@ impl Debug for InternalServerError {
 }
 
 impl warp::reject::Reject for InternalServerError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_invalid_mask_is_unprocessable() {
+        let mut request = request::Request {
+            mask: Some(b"not an image".to_vec()),
+            ..Default::default()
+        };
+
+        let e = match load_mask(
+            &mut request,
+            &config::Config::default(),
+            &MapCache::default(),
+        )
+        .await
+        {
+            Err(e) => e,
+            Ok(_) => panic!("garbage decoded as a mask"),
+        };
+        assert!(matches!(
+            e.downcast_ref::<Error>(),
+            Some(Error::InvalidMask(_))
+        ));
+        assert_eq!(
+            failed(e).unwrap().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mask_fits_supersampled_render() -> Result<()> {
+        let mut config = config::Config::default();
+        config.limits.max_width = 400;
+        config.limits.max_height = 400;
+        config.limits.max_render_pixels = 400 * 400;
+        config.antialias.max_supersample = 4;
+
+        let mut bytes = vec![];
+        DynamicImage::new_rgb8(800, 400).write_to(
+            &mut std::io::Cursor::new(&mut bytes),
+            image::ImageFormat::Png,
+        )?;
+        let mut request = request::Request {
+            mask: Some(bytes),
+            supersample: Some(3),
+            ..Default::default()
+        };
+
+        load_mask(&mut request, &config, &MapCache::default()).await?;
+        assert_eq!((request.width, request.height), (188, 94));
+        assert!(config
+            .check_limits(request.width, request.height, request.supersample)
+            .is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+
+/// Shared drain state, flipped once a termination signal arrives.
+#[derive(Default)]
+pub struct Shutdown {
+    draining: AtomicBool,
+    notify: Notify,
+}
+
+impl Shutdown {
+    pub fn new_arc() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn start(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    /// Resolves once draining has started, immediately if it already has.
+    pub async fn wait(&self) {
+        let notified = self.notify.notified();
+        if self.is_draining() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Waits for SIGTERM (sent by kubernetes on pod termination) or ctrl-c.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("SIGTERM handler can be installed");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wait_after_start() {
+        let shutdown = Shutdown::new_arc();
+        assert!(!shutdown.is_draining());
+
+        let waiter = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.wait().await }
+        });
+
+        shutdown.start();
+        assert!(shutdown.is_draining());
+
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("waiter is released")
+            .unwrap();
+
+        // already draining, resolves right away
+        shutdown.wait().await;
+    }
+}
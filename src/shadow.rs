@@ -0,0 +1,210 @@
+use anyhow::{anyhow, Result};
+use image::{imageops, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use three_d::{
+    vec2, vec3, AxisAlignedBoundingBox, Blend, Camera, ClearState, ColorMaterial, CpuMesh,
+    CpuTexture, Cull, DepthMaterial, DepthTexture2D, Geometry, Gm, Indices, Mesh, Model, Positions,
+    RenderStates, Srgba, Texture2DRef, Viewport, Wrapping,
+};
+use three_d_asset::TextureData;
+
+/// Soft shadow on the ground beneath the model, strongest where the model touches the ground.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct ContactShadow {
+    /// Opacity (0-1) right under the parts touching the ground.
+    pub opacity: f32,
+    /// Height above the ground, as a fraction of the model height, where the shadow fades out.
+    pub fade: f32,
+    /// Blur as a fraction of the ground size.
+    pub blur: f32,
+    /// Ground around the footprint, as a fraction of the footprint size, room for the blur.
+    pub spread: f32,
+    /// Size of the shadow map in pixels.
+    pub resolution: u32,
+}
+
+impl Default for ContactShadow {
+    fn default() -> Self {
+        Self {
+            opacity: 0.6,
+            fade: 0.2,
+            blur: 0.02,
+            spread: 0.15,
+            resolution: 256,
+        }
+    }
+}
+
+/// Builds a ground plane under the model textured with its contact shadow.
+///
+/// The model is rendered from below with an orthographic camera, the linear depth
+/// of that pass is the height of the lowest surface above every point of the ground.
+pub fn ground(
+    context: &three_d::Context,
+    model: &Model<ColorMaterial>,
+    shadow: &ContactShadow,
+) -> Result<Gm<Mesh, ColorMaterial>> {
+    let mut aabb = AxisAlignedBoundingBox::EMPTY;
+    model
+        .iter()
+        .for_each(|part| aabb.expand_with_aabb(&part.aabb()));
+    if aabb.is_empty() || aabb.is_infinite() {
+        return Err(anyhow!("model has no bounds for a contact shadow"));
+    }
+
+    let (min, center, size) = (aabb.min(), aabb.center(), aabb.size());
+    let ground_size = ground_size(size.x.max(size.z), shadow.spread);
+    let fade_height = fade_height(size.y, shadow.fade);
+    // the camera sits a bit below the ground, so surfaces on the ground are not clipped
+    let offset = fade_height * 0.01;
+    let resolution = shadow.resolution.clamp(16, 2048);
+
+    let camera = Camera::new_orthographic(
+        Viewport::new_at_origo(resolution, resolution),
+        vec3(center.x, min.y - offset, center.z),
+        vec3(center.x, min.y + 1., center.z),
+        vec3(0., 0., 1.),
+        ground_size,
+        0.,
+        offset + fade_height,
+    );
+
+    let mut depth_texture = DepthTexture2D::new::<f32>(
+        context,
+        resolution,
+        resolution,
+        Wrapping::ClampToEdge,
+        Wrapping::ClampToEdge,
+    );
+    let material = DepthMaterial {
+        render_states: RenderStates {
+            cull: Cull::None,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let depths = depth_texture
+        .as_depth_target()
+        .clear(ClearState::depth(1.))
+        .render_with_material(&material, &camera, model.iter(), &[])
+        .read();
+
+    let opacity = shadow.opacity.clamp(0., 1.);
+    // depth rows start at the bottom of the view (-z), images at the top (+z)
+    let mut shadow_map = RgbaImage::from_fn(resolution, resolution, |x, y| {
+        let depth = depths[((resolution - 1 - y) * resolution + x) as usize];
+        let strength = strength(depth, offset, fade_height);
+        Rgba([0, 0, 0, (strength * opacity * 255.).round() as u8])
+    });
+
+    let sigma = blur_sigma(shadow.blur, resolution);
+    if sigma > 0. {
+        shadow_map = imageops::blur(&shadow_map, sigma);
+    }
+
+    let texture = CpuTexture {
+        data: TextureData::RgbaU8(
+            shadow_map
+                .pixels()
+                .map(|pixel| pixel.0)
+                .collect::<Vec<[u8; 4]>>(),
+        ),
+        width: resolution,
+        height: resolution,
+        wrap_s: Wrapping::ClampToEdge,
+        wrap_t: Wrapping::ClampToEdge,
+        ..Default::default()
+    };
+
+    // slightly below the lowest point to avoid z-fighting with the model
+    let y = min.y - offset;
+    let half = ground_size / 2.;
+    let plane = CpuMesh {
+        positions: Positions::F32(vec![
+            vec3(center.x - half, y, center.z + half),
+            vec3(center.x + half, y, center.z + half),
+            vec3(center.x + half, y, center.z - half),
+            vec3(center.x - half, y, center.z - half),
+        ]),
+        indices: Indices::U8(vec![0, 1, 2, 2, 3, 0]),
+        uvs: Some(vec![vec2(0., 0.), vec2(1., 0.), vec2(1., 1.), vec2(0., 1.)]),
+        ..Default::default()
+    };
+
+    let material = ColorMaterial {
+        color: Srgba::WHITE,
+        texture: Some(Texture2DRef::from_cpu_texture(context, &texture)),
+        is_transparent: true,
+        render_states: RenderStates {
+            cull: Cull::None,
+            blend: Blend::STANDARD_TRANSPARENCY,
+            ..Default::default()
+        },
+    };
+
+    Ok(Gm::new(Mesh::new(context, &plane), material))
+}
+
+/// Side of the square ground, the footprint plus the spread on both sides.
+fn ground_size(footprint: f32, spread: f32) -> f32 {
+    footprint * (1. + 2. * spread.max(0.))
+}
+
+/// Height above the ground where the shadow is gone, never zero so strengths stay finite.
+fn fade_height(model_height: f32, fade: f32) -> f32 {
+    (model_height * fade).max(f32::EPSILON)
+}
+
+/// Shadow strength (0-1) for a linear depth of the pass from below.
+///
+/// The depth spans from the camera, `offset` below the ground, to `fade_height` above it.
+fn strength(depth: f32, offset: f32, fade_height: f32) -> f32 {
+    let height = depth * (offset + fade_height) - offset;
+    (1. - height / fade_height).clamp(0., 1.)
+}
+
+/// Gaussian blur sigma in pixels of the shadow map.
+fn blur_sigma(blur: f32, resolution: u32) -> f32 {
+    blur * resolution as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ground_size() {
+        assert_eq!(ground_size(2., 0.25), 3.);
+        // a negative spread never shrinks the ground below the footprint
+        assert_eq!(ground_size(2., -1.), 2.);
+    }
+
+    #[test]
+    fn test_fade_height() {
+        assert_eq!(fade_height(10., 0.2), 2.);
+        assert_eq!(fade_height(0., 0.2), f32::EPSILON);
+        assert_eq!(fade_height(10., 0.), f32::EPSILON);
+    }
+
+    #[test]
+    fn test_strength() {
+        let (offset, fade_height) = (0.02, 2.);
+        let depth = |height: f32| (height + offset) / (offset + fade_height);
+
+        // touching the ground
+        assert!((strength(depth(0.), offset, fade_height) - 1.).abs() < 1e-6);
+        assert!((strength(depth(1.), offset, fade_height) - 0.5).abs() < 1e-6);
+        // at and above the fade height, or nothing rendered (cleared to 1)
+        assert_eq!(strength(depth(2.), offset, fade_height), 0.);
+        assert_eq!(strength(1., offset, fade_height), 0.);
+        // below the ground, between the camera and the plane
+        assert_eq!(strength(0., offset, fade_height), 1.);
+    }
+
+    #[test]
+    fn test_blur_sigma() {
+        assert_eq!(blur_sigma(0.02, 256), 5.12);
+        assert_eq!(blur_sigma(0., 256), 0.);
+    }
+}
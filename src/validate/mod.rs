@@ -0,0 +1,519 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use clap::{Arg, ArgMatches, Command};
+use gltf::camera::Projection;
+use gltf::mesh::Semantic;
+use gltf::Node;
+use nalgebra::Matrix4;
+use serde::Serialize;
+
+use crate::manifest::Manifest;
+
+pub struct Validate {}
+
+#[async_trait]
+impl crate::Subcommand for Validate {
+    fn get_subcommand(&self) -> Command {
+        Command::new("validate")
+            .arg(
+                Arg::new("input")
+                    .required(true)
+                    .long_help("glb/gltf file or directory containing multiple"),
+            )
+            .arg(
+                Arg::new("textures")
+                    .long("textures")
+                    .value_parser(clap::value_parser!(usize))
+                    .long_help("number of textures the models are rendered with, by default the number of default textures of the manifest"),
+            )
+            .arg(
+                Arg::new("json")
+                    .long("json")
+                    .action(clap::ArgAction::SetTrue)
+                    .long_help("print the reports as json"),
+            )
+            .about("Check models for problems that break a render, fails if any model has errors")
+    }
+
+    async fn run(&self, matches: &ArgMatches) -> Result<()> {
+        let input = matches.get_one::<String>("input").unwrap();
+        let options = Options {
+            textures: matches.get_one::<usize>("textures").copied(),
+        };
+
+        let reports = validate_all(Path::new(input), &options)?;
+
+        if matches.get_flag("json") {
+            println!("{}", serde_json::to_string_pretty(&reports)?);
+        } else {
+            reports.iter().for_each(|report| println!("{}", report));
+        }
+
+        let invalid = reports.iter().filter(|report| !report.is_valid()).count();
+        if invalid > 0 {
+            return Err(anyhow!(
+                "{} of {} models have errors",
+                invalid,
+                reports.len()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Options {
+    /// Number of textures a model is rendered with, wins over the manifest's default textures.
+    pub textures: Option<usize>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The model does not render or renders wrong.
+    Error,
+    /// The model renders, but likely not as intended.
+    Warning,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Issue {
+    pub severity: Severity,
+    pub check: &'static str,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub path: String,
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    pub fn is_valid(&self) -> bool {
+        self.issues
+            .iter()
+            .all(|issue| issue.severity != Severity::Error)
+    }
+
+    fn error(&mut self, check: &'static str, message: String) {
+        self.issues.push(Issue {
+            severity: Severity::Error,
+            check,
+            message,
+        });
+    }
+
+    fn warning(&mut self, check: &'static str, message: String) {
+        self.issues.push(Issue {
+            severity: Severity::Warning,
+            check,
+            message,
+        });
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.issues.is_empty() {
+            return write!(f, "{}: ok", self.path);
+        }
+
+        write!(f, "{}:", self.path)?;
+        for issue in &self.issues {
+            let severity = match issue.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            write!(f, "\n  {} [{}] {}", severity, issue.check, issue.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Validates a model or every glb/gltf in a directory, sorted by path.
+pub fn validate_all(input: &Path, options: &Options) -> Result<Vec<Report>> {
+    if !input.is_dir() {
+        return Ok(vec![validate(input, options)]);
+    }
+
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(input)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            matches!(
+                path.extension().and_then(|extension| extension.to_str()),
+                Some("glb" | "gltf")
+            )
+        })
+        .collect();
+    paths.sort();
+
+    Ok(paths.iter().map(|path| validate(path, options)).collect())
+}
+
+/// Checks a model the way the render uses it: the scene, its camera,
+/// meshes and texture coordinates, materials against the textures and node transforms.
+pub fn validate(path: &Path, options: &Options) -> Report {
+    let mut report = Report {
+        path: path.display().to_string(),
+        issues: vec![],
+    };
+
+    let manifest = match Manifest::load(path) {
+        Ok(manifest) => manifest.unwrap_or_default(),
+        Err(e) => {
+            report.error("manifest", format!("{:#}", e));
+            Manifest::default()
+        }
+    };
+
+    let gltf = match gltf::Gltf::open(path) {
+        Ok(gltf) => gltf,
+        Err(e) => {
+            report.error("gltf", e.to_string());
+            return report;
+        }
+    };
+    let base = path.parent().unwrap_or(Path::new(""));
+    let buffers = match gltf::import_buffers(&gltf.document, Some(base), gltf.blob.clone()) {
+        Ok(buffers) => buffers,
+        Err(e) => {
+            report.error("gltf", format!("loading buffers: {}", e));
+            return report;
+        }
+    };
+
+    let scene = match crate::gltf::scene_index(&gltf.document, None) {
+        Ok(index) => gltf.document.scenes().nth(index).unwrap(),
+        Err(e) => {
+            report.error("scene", e.to_string());
+            return report;
+        }
+    };
+    if gltf.document.default_scene().is_none() {
+        report.warning(
+            "scene",
+            "no default scene, the first scene is rendered".to_string(),
+        );
+    }
+
+    let mut nodes = vec![];
+    for node in scene.nodes() {
+        collect(node, Matrix4::identity(), &mut nodes);
+    }
+
+    check_transforms(&nodes, &mut report);
+    check_camera(&nodes, &manifest, &mut report);
+    check_meshes(&nodes, &buffers, &mut report);
+    check_textures(&nodes, &manifest, options, &mut report);
+
+    report
+}
+
+/// A node of the rendered scene with its world matrix.
+struct Visited<'a> {
+    node: Node<'a>,
+    world: Matrix4<f32>,
+}
+
+impl Visited<'_> {
+    fn name(&self) -> String {
+        self.node
+            .name()
+            .map(String::from)
+            .unwrap_or_else(|| format!("index {}", self.node.index()))
+    }
+}
+
+fn collect<'a>(node: Node<'a>, parent: Matrix4<f32>, nodes: &mut Vec<Visited<'a>>) {
+    let local: Matrix4<f32> = node.transform().matrix().into();
+    let world = parent * local;
+
+    let children: Vec<Node> = node.children().collect();
+    nodes.push(Visited { node, world });
+    for child in children {
+        collect(child, world, nodes);
+    }
+}
+
+/// The same test the loader skips nodes with, so small scales (e.g. a model in millimetres) pass.
+fn is_invertible(matrix: &Matrix4<f32>) -> bool {
+    matrix.determinant() != 0. && matrix.iter().all(|value| value.is_finite())
+}
+
+fn check_transforms(nodes: &[Visited], report: &mut Report) {
+    for visited in nodes {
+        let local: Matrix4<f32> = visited.node.transform().matrix().into();
+        if local.iter().any(|value| !value.is_finite()) {
+            report.error(
+                "transform",
+                format!("node {} has an invalid transform", visited.name()),
+            );
+        } else if !is_invertible(&local) {
+            // a zero scale is a common way to hide a node
+            report.warning(
+                "transform",
+                format!(
+                    "node {} has a non-invertible transform (e.g. a zero scale), it and its children are not rendered",
+                    visited.name()
+                ),
+            );
+        }
+    }
+}
+
+fn check_camera(nodes: &[Visited], manifest: &Manifest, report: &mut Report) {
+    let cameras: Vec<&Visited> = nodes
+        .iter()
+        .filter(|visited| visited.node.camera().is_some())
+        .collect();
+    let perspective: Vec<&&Visited> = cameras
+        .iter()
+        .filter(|visited| {
+            visited
+                .node
+                .camera()
+                .is_some_and(|camera| matches!(camera.projection(), Projection::Perspective(_)))
+        })
+        .collect();
+
+    if perspective.is_empty() {
+        let message = match cameras.is_empty() {
+            true => "no camera in the scene".to_string(),
+            false => "only orthographic cameras, the render needs a perspective camera".to_string(),
+        };
+        report.error("camera", message);
+        return;
+    }
+
+    let camera = match &manifest.camera {
+        Some(name) => match perspective
+            .iter()
+            .find(|visited| visited.node.name() == Some(name))
+        {
+            Some(camera) => camera,
+            None => {
+                report.error(
+                    "camera",
+                    format!("camera {} of the manifest is not in the scene", name),
+                );
+                return;
+            }
+        },
+        None => perspective[0],
+    };
+
+    if !is_invertible(&camera.world) {
+        report.error(
+            "camera",
+            format!(
+                "camera {} has a non-invertible world transform",
+                camera.name()
+            ),
+        );
+    }
+}
+
+fn check_meshes(nodes: &[Visited], buffers: &[gltf::buffer::Data], report: &mut Report) {
+    let meshes: Vec<&Visited> = nodes
+        .iter()
+        .filter(|visited| visited.node.mesh().is_some())
+        .collect();
+    if meshes.is_empty() {
+        report.error("mesh", "no mesh in the scene".to_string());
+        return;
+    }
+
+    for visited in meshes {
+        let mesh = visited.node.mesh().unwrap();
+        for primitive in mesh.primitives() {
+            if primitive.get(&Semantic::Positions).is_none() {
+                continue;
+            }
+
+            let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &**data));
+            let Some(tex_coords) = reader.read_tex_coords(0) else {
+                report.error(
+                    "uv",
+                    format!(
+                        "mesh {} has no texture coordinates, textures cannot be applied",
+                        visited.name()
+                    ),
+                );
+                continue;
+            };
+
+            let (mut min, mut max) = ([f32::MAX; 2], [f32::MIN; 2]);
+            let mut finite = true;
+            for uv in tex_coords.into_f32() {
+                for i in 0..2 {
+                    finite &= uv[i].is_finite();
+                    min[i] = min[i].min(uv[i]);
+                    max[i] = max[i].max(uv[i]);
+                }
+            }
+
+            if !finite {
+                report.error(
+                    "uv",
+                    format!("mesh {} has invalid texture coordinates", visited.name()),
+                );
+            } else if min
+                .iter()
+                .chain(max.iter())
+                .any(|v| !(-0.001..=1.001).contains(v))
+            {
+                report.warning(
+                    "uv",
+                    format!(
+                        "texture coordinates of mesh {} range from ({:.3}, {:.3}) to ({:.3}, {:.3}), outside of 0-1 the texture repeats",
+                        visited.name(),
+                        min[0],
+                        min[1],
+                        max[0],
+                        max[1]
+                    ),
+                );
+            }
+        }
+    }
+}
+
+fn check_textures(nodes: &[Visited], manifest: &Manifest, options: &Options, report: &mut Report) {
+    let mesh_names: BTreeSet<String> = nodes
+        .iter()
+        .filter(|visited| visited.node.mesh().is_some())
+        .map(|visited| visited.name())
+        .collect();
+    let materials: BTreeSet<Option<usize>> = nodes
+        .iter()
+        .filter_map(|visited| visited.node.mesh())
+        .flat_map(|mesh| {
+            mesh.primitives()
+                .map(|primitive| primitive.material().index())
+        })
+        .collect();
+
+    for name in manifest.textures.keys() {
+        if !mesh_names.contains(name) {
+            report.error(
+                "textures",
+                format!("mesh {} of the manifest is not in the scene", name),
+            );
+        }
+    }
+
+    let expected = options.textures.or_else(|| {
+        (!manifest.default_textures.is_empty()).then_some(manifest.default_textures.len())
+    });
+    let Some(expected) = expected else {
+        return;
+    };
+
+    if materials.len() != expected {
+        report.warning(
+            "textures",
+            format!(
+                "{} materials but {} textures expected",
+                materials.len(),
+                expected
+            ),
+        );
+    }
+    for (name, index) in &manifest.textures {
+        if *index >= expected {
+            report.error(
+                "textures",
+                format!(
+                    "mesh {} uses texture {} of {} textures",
+                    name, index, expected
+                ),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let report = validate(Path::new("testdata/canary.glb"), &Options::default());
+        assert!(report.is_valid(), "{}", report);
+
+        let report = validate(
+            Path::new("testdata/canary.glb"),
+            &Options { textures: Some(3) },
+        );
+        assert!(report.is_valid());
+        assert_eq!(report.issues[0].check, "textures");
+
+        let report = validate(Path::new("testdata/test.png"), &Options::default());
+        assert!(!report.is_valid());
+        assert_eq!(report.issues[0].check, "gltf");
+    }
+
+    #[test]
+    fn test_validate_all() -> Result<()> {
+        let reports = validate_all(Path::new("testdata"), &Options::default())?;
+        let paths: Vec<&str> = reports.iter().map(|report| report.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "testdata/canary.glb",
+                "testdata/duvet-cover.gltf",
+                "testdata/iphone.gltf"
+            ]
+        );
+        // the gltf files reference a buffer.bin that is not checked in
+        assert!(reports[0].is_valid());
+        assert!(!reports[1].is_valid());
+        Ok(())
+    }
+
+    fn issues(path: &str) -> Vec<(Severity, &'static str)> {
+        validate(Path::new(path), &Options::default())
+            .issues
+            .iter()
+            .map(|issue| (issue.severity, issue.check))
+            .collect()
+    }
+
+    #[test]
+    fn test_fixtures() {
+        // copies of the canary with one problem each
+        assert_eq!(
+            issues("testdata/validate/no-uv.glb"),
+            vec![(Severity::Error, "uv")]
+        );
+        assert_eq!(
+            issues("testdata/validate/orthographic.glb"),
+            vec![(Severity::Error, "camera")]
+        );
+        assert_eq!(
+            issues("testdata/validate/missing-camera.glb"),
+            vec![(Severity::Error, "camera")]
+        );
+        assert_eq!(
+            issues("testdata/validate/texture-index.glb"),
+            vec![(Severity::Error, "textures")]
+        );
+
+        // a node scaled to 0.001 is fine, one scaled to zero is hidden
+        let report = validate(
+            Path::new("testdata/validate/degenerate.glb"),
+            &Options::default(),
+        );
+        assert!(report.is_valid(), "{}", report);
+        assert_eq!(report.issues.len(), 1);
+        assert_eq!(report.issues[0].severity, Severity::Warning);
+        assert!(report.issues[0].message.contains("node hidden"));
+    }
+}